@@ -0,0 +1,256 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use encoding_rs::Encoding;
+use lsp_types::FormattingOptions;
+
+use crate::{PositionEncoding, ServerCommand};
+
+/// How interfaces are inferred, built with [`InferConfig::builder`].
+#[derive(Debug, Clone)]
+pub struct InferConfig {
+    pub server: ServerCommand,
+    /// Other roots given to the server along with the project root, to resolve modules of other
+    /// projects.
+    pub workspace_folders: Vec<PathBuf>,
+    /// How long to wait for each request to the server, forever when it's `None`.
+    pub request_timeout: Option<Duration>,
+    /// How long to wait for the server to index the project before inferring anyway, forever
+    /// when it's `None`.
+    pub index_timeout: Option<Duration>,
+    /// How long to wait for the server to format an interface before leaving it unformatted,
+    /// forever when it's `None`.
+    pub format_timeout: Option<Duration>,
+    /// How long to wait for the server to exit once it's shut down before killing it.
+    pub kill_timeout: Duration,
+    /// How the interfaces are formatted, they're left as the server inferred them when it's
+    /// `None`.
+    pub formatting: Option<FormattingOptions>,
+    /// Whether the doc comments of the existing interfaces are carried over.
+    pub merge_docs: bool,
+    /// How many times an empty interface is inferred again, as the server may not be done
+    /// indexing.
+    pub retries: u32,
+    /// The language the documents are opened as, detected from their extension when it's
+    /// `None`.
+    pub language_id: Option<String>,
+    /// The encoding of the positions sent by the server when it's forced, as a workaround for
+    /// servers that don't use the one they agreed to.
+    pub position_encoding: Option<PositionEncoding>,
+    /// The size in bytes of the largest source inferred, there's no limit when it's `None`.
+    pub max_size: Option<u64>,
+    /// Whether the interfaces end with a newline, they never end with more than one.
+    pub final_newline: bool,
+    /// Whether the sources are saved once opened, for servers that only compute some of what
+    /// they know about a file on save.
+    pub save_before_infer: bool,
+    /// The names of the only declarations kept, along with the types and modules they refer to.
+    /// All of them are kept when it's `None`.
+    pub only: Option<HashSet<String>>,
+    /// Whether the project root is also sent as the deprecated `rootUri` and `rootPath`, which
+    /// some servers still detect the project from. It's only sent when there are no other
+    /// workspace folders when it's `None`.
+    pub root_uri: Option<bool>,
+    /// The encoding of the sources and of the existing interfaces, only UTF-8 is sent to the
+    /// server.
+    pub encoding: &'static Encoding,
+    /// Whether the options of the nearest `.ocamlformat` are sent along with the formatting
+    /// ones, so the interfaces are formatted like the rest of the project.
+    pub ocamlformat: bool,
+    /// Whether the declarations missing from the existing interface are appended to it, leaving
+    /// what's already there untouched, instead of replacing it.
+    pub append_to_existing: bool,
+    /// Whether the top level `include`s are removed from the interfaces, so they don't expose
+    /// what they include.
+    pub strip_includes: bool,
+    /// Whether the top level `open`s are removed from the interfaces.
+    pub strip_opens: bool,
+    /// The template of the URLs of the documents and workspace folders sent to the server, for
+    /// servers of virtual or remote workspaces, `{path}` being replaced by the absolute path
+    /// they're at. They're `file://` URLs when it's `None`.
+    pub uri_template: Option<String>,
+}
+
+impl InferConfig {
+    pub fn builder() -> InferConfigBuilder {
+        InferConfigBuilder::default()
+    }
+}
+
+impl Default for InferConfig {
+    fn default() -> Self {
+        InferConfig::builder().build()
+    }
+}
+
+/// Builds an [`InferConfig`], starting from the defaults.
+#[derive(Debug, Clone)]
+pub struct InferConfigBuilder {
+    config: InferConfig,
+    formatting: FormattingOptions,
+    no_format: bool,
+}
+
+impl Default for InferConfigBuilder {
+    fn default() -> Self {
+        Self {
+            config: InferConfig {
+                server: ServerCommand::default(),
+                workspace_folders: Vec::new(),
+                request_timeout: Some(Duration::from_secs(60)),
+                index_timeout: Some(Duration::from_secs(30)),
+                format_timeout: Some(Duration::from_secs(60)),
+                kill_timeout: Duration::from_secs(2),
+                formatting: None,
+                merge_docs: true,
+                retries: 1,
+                language_id: None,
+                position_encoding: None,
+                max_size: Some(5 * 1024 * 1024),
+                final_newline: true,
+                save_before_infer: false,
+                only: None,
+                root_uri: None,
+                encoding: encoding_rs::UTF_8,
+                ocamlformat: true,
+                append_to_existing: false,
+                strip_includes: false,
+                strip_opens: false,
+                uri_template: None,
+            },
+            formatting: FormattingOptions {
+                tab_size: 2,
+                insert_spaces: true,
+                trim_trailing_whitespace: Some(true),
+                insert_final_newline: Some(true),
+                ..FormattingOptions::default()
+            },
+            no_format: false,
+        }
+    }
+}
+
+impl InferConfigBuilder {
+    pub fn server(mut self, server: ServerCommand) -> Self {
+        self.config.server = server;
+        self
+    }
+
+    pub fn workspace_folder(mut self, folder: impl Into<PathBuf>) -> Self {
+        self.config.workspace_folders.push(folder.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.config.request_timeout = timeout.into();
+        self
+    }
+
+    pub fn index_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.config.index_timeout = timeout.into();
+        self
+    }
+
+    pub fn format_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.config.format_timeout = timeout.into();
+        self
+    }
+
+    pub fn kill_timeout(mut self, timeout: Duration) -> Self {
+        self.config.kill_timeout = timeout;
+        self
+    }
+
+    pub fn formatting(mut self, formatting: FormattingOptions) -> Self {
+        self.formatting = formatting;
+        self
+    }
+
+    pub fn no_format(mut self, no_format: bool) -> Self {
+        self.no_format = no_format;
+        self
+    }
+
+    pub fn merge_docs(mut self, merge_docs: bool) -> Self {
+        self.config.merge_docs = merge_docs;
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.config.retries = retries;
+        self
+    }
+
+    pub fn language_id(mut self, language_id: impl Into<String>) -> Self {
+        self.config.language_id = Some(language_id.into());
+        self
+    }
+
+    pub fn position_encoding(mut self, encoding: impl Into<Option<PositionEncoding>>) -> Self {
+        self.config.position_encoding = encoding.into();
+        self
+    }
+
+    pub fn max_size(mut self, max_size: impl Into<Option<u64>>) -> Self {
+        self.config.max_size = max_size.into();
+        self
+    }
+
+    pub fn final_newline(mut self, final_newline: bool) -> Self {
+        self.config.final_newline = final_newline;
+        self
+    }
+
+    pub fn save_before_infer(mut self, save_before_infer: bool) -> Self {
+        self.config.save_before_infer = save_before_infer;
+        self
+    }
+
+    pub fn only(mut self, names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.config.only = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn root_uri(mut self, root_uri: impl Into<Option<bool>>) -> Self {
+        self.config.root_uri = root_uri.into();
+        self
+    }
+
+    pub fn encoding(mut self, encoding: &'static Encoding) -> Self {
+        self.config.encoding = encoding;
+        self
+    }
+
+    pub fn ocamlformat(mut self, ocamlformat: bool) -> Self {
+        self.config.ocamlformat = ocamlformat;
+        self
+    }
+
+    pub fn append_to_existing(mut self, append_to_existing: bool) -> Self {
+        self.config.append_to_existing = append_to_existing;
+        self
+    }
+
+    pub fn strip_includes(mut self, strip_includes: bool) -> Self {
+        self.config.strip_includes = strip_includes;
+        self
+    }
+
+    pub fn strip_opens(mut self, strip_opens: bool) -> Self {
+        self.config.strip_opens = strip_opens;
+        self
+    }
+
+    pub fn uri_template(mut self, template: impl Into<String>) -> Self {
+        self.config.uri_template = Some(template.into());
+        self
+    }
+
+    pub fn build(self) -> InferConfig {
+        InferConfig {
+            formatting: (!self.no_format).then_some(self.formatting),
+            ..self.config
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+
+/// The kinds of top level declarations doc comments are carried over for, with the keywords
+/// starting them. Longer keywords come first so `module type` isn't taken for a `module`.
+const KINDS: &[&str] = &[
+    "module type",
+    "class type",
+    "val",
+    "external",
+    "type",
+    "exception",
+    "module",
+    "class",
+];
+
+/// The kind and name of the declaration starting `line`, if it's a top level one.
+pub(crate) fn declaration(line: &str) -> Option<(&'static str, &str)> {
+    if line.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let (kind, rest) = KINDS.iter().find_map(|kind| {
+        let rest = line.strip_prefix(kind)?;
+        rest.starts_with(char::is_whitespace)
+            .then(|| (*kind, rest.trim_start()))
+    })?;
+
+    let mut rest = rest;
+    if kind == "module" {
+        rest = rest.strip_prefix("rec ").unwrap_or(rest).trim_start();
+    }
+    if kind == "type" {
+        rest = rest.strip_prefix("nonrec ").unwrap_or(rest).trim_start();
+        // skip the type parameters, `'a t` or `('a, 'b) t`
+        if let Some(params) = rest.strip_prefix('(') {
+            rest = params.split_once(')')?.1.trim_start();
+        } else if rest.starts_with(['\'', '+', '-', '_']) {
+            rest = rest.split_once(char::is_whitespace)?.1.trim_start();
+        }
+    }
+
+    let end = rest
+        .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\''))
+        .unwrap_or(rest.len());
+    let name = &rest[..end];
+    (!name.is_empty()).then_some((kind, name))
+}
+
+/// The doc comments of `mli` by the declaration they're attached to, only comments right before
+/// a top level declaration count. Declarations found twice are left out, as it's unclear which
+/// one a comment belongs to.
+fn doc_comments(mli: &str) -> HashMap<(&'static str, &str), String> {
+    let mut comments = HashMap::new();
+    let mut duplicates = Vec::new();
+    let mut comment: Option<String> = None;
+    let mut depth = 0;
+
+    for line in mli.lines() {
+        if depth > 0 {
+            if let Some(comment) = &mut comment {
+                comment.push('\n');
+                comment.push_str(line);
+            }
+            depth += comment_depth(line);
+            continue;
+        }
+
+        if is_doc_comment(line) {
+            comment = Some(line.to_string());
+            depth = comment_depth(line);
+            continue;
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(key) = declaration(line) {
+            if let Some(comment) = comment.take() {
+                if comments.insert(key, comment).is_some() {
+                    duplicates.push(key);
+                }
+            } else if comments.contains_key(&key) {
+                duplicates.push(key);
+            }
+        }
+        comment = None;
+    }
+
+    for key in duplicates {
+        comments.remove(&key);
+    }
+    comments
+}
+
+/// Whether `line` starts a doc comment, `(***` starts a regular comment.
+fn is_doc_comment(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("(**") && !line.starts_with("(***")
+}
+
+/// How many comments `line` opens minus how many it closes.
+pub(crate) fn comment_depth(line: &str) -> i32 {
+    line.matches("(*").count() as i32 - line.matches("*)").count() as i32
+}
+
+/// Carries the doc comments of the declarations of `old` over to the same declarations in `new`,
+/// matched by kind and name. Declarations of `new` that already have a doc comment are left
+/// alone.
+pub(crate) fn merge_doc_comments(old: &str, new: &str) -> String {
+    let comments = doc_comments(old);
+    if comments.is_empty() {
+        return new.to_string();
+    }
+
+    let mut merged = String::with_capacity(new.len());
+    let mut documented = false;
+    let mut depth = 0;
+    for line in new.split_inclusive('\n') {
+        if depth > 0 {
+            depth += comment_depth(line);
+        } else if is_doc_comment(line) {
+            depth = comment_depth(line);
+            documented = true;
+        } else if !line.trim().is_empty() {
+            if !documented {
+                if let Some(comment) = declaration(line).and_then(|key| comments.get(&key)) {
+                    merged.push_str(comment);
+                    merged.push('\n');
+                }
+            }
+            documented = false;
+        }
+        merged.push_str(line);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carries_multi_line_doc_comments_over() {
+        let old = "(** Adds [x]\n    to [y]. *)\nval add : int -> int -> int\n";
+        assert_eq!(
+            merge_doc_comments(old, "val add : int -> int -> int\n"),
+            "(** Adds [x]\n    to [y]. *)\nval add : int -> int -> int\n"
+        );
+    }
+
+    #[test]
+    fn carries_doc_comments_with_nested_comments_over() {
+        let old =
+            "(** Doc (* nested\n    *) still doc\n    val y : int *)\nval x : int\nval y : int\n";
+        assert_eq!(
+            merge_doc_comments(old, "val x : int\nval y : int\n"),
+            "(** Doc (* nested\n    *) still doc\n    val y : int *)\nval x : int\nval y : int\n"
+        );
+    }
+
+    #[test]
+    fn matches_declarations_by_kind_and_name() {
+        let old =
+            "(** Type. *)\ntype t\n(** Value. *)\nval t : t\n(** Recursive. *)\nmodule rec M : S\n";
+        assert_eq!(
+            merge_doc_comments(old, "module rec M : S\nval t : t\ntype t\n"),
+            "(** Recursive. *)\nmodule rec M : S\n(** Value. *)\nval t : t\n(** Type. *)\ntype t\n"
+        );
+    }
+
+    #[test]
+    fn leaves_duplicate_names_undocumented() {
+        let old = "(** First. *)\nval x : int\n(** Second. *)\nval x : string\n(** Doc of y. *)\nval y : int\n";
+        assert_eq!(
+            merge_doc_comments(old, "val x : string\nval x : int\nval y : int\n"),
+            "val x : string\nval x : int\n(** Doc of y. *)\nval y : int\n"
+        );
+    }
+
+    #[test]
+    fn keeps_the_doc_comments_of_the_inferred_interface() {
+        let old = "(** Old. *)\nval x : int\n(** Doc of y. *)\nval y : int\n";
+        let new = "(** New,\n    on two lines. *)\nval x : int\n\nval y : int\n";
+        assert_eq!(
+            merge_doc_comments(old, new),
+            "(** New,\n    on two lines. *)\nval x : int\n\n(** Doc of y. *)\nval y : int\n"
+        );
+    }
+
+    #[test]
+    fn regular_comments_are_not_carried_over() {
+        let old = "(* Not a doc. *)\nval x : int\n(*** Neither. *)\nval y : int\n";
+        assert_eq!(
+            merge_doc_comments(old, "val x : int\nval y : int\n"),
+            "val x : int\nval y : int\n"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use lsp_types::{Position, PositionEncodingKind, TextEdit};
+use ropey::{Rope, RopeSlice};
+
+/// How the `character` of LSP positions is counted, negotiated with the server on initialize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionEncoding {
+    Utf8,
+    /// The default of the protocol, used when the server doesn't pick one.
+    #[default]
+    Utf16,
+}
+
+impl PositionEncoding {
+    /// The encodings the client supports, by preference.
+    pub(crate) fn supported() -> Vec<PositionEncodingKind> {
+        vec![PositionEncodingKind::UTF8, PositionEncodingKind::UTF16]
+    }
+
+    /// The encoding picked by the server, which falls back to UTF-16 when it picks none, or one
+    /// the client didn't advertise.
+    pub(crate) fn negotiated(kind: Option<&PositionEncodingKind>) -> Self {
+        match kind {
+            Some(kind) if *kind == PositionEncodingKind::UTF8 => Self::Utf8,
+            _ => Self::Utf16,
+        }
+    }
+}
+
+/// The line without its terminator, ropey keeps `\r\n` in the line it ends but positions can't
+/// point inside it.
+fn line_content(line: RopeSlice) -> RopeSlice {
+    let mut end = line.len_chars();
+    while end > 0 && matches!(line.char(end - 1), '\n' | '\r') {
+        end -= 1;
+    }
+    line.slice(..end)
+}
+
+/// Converts an LSP position into a char index of the rope, a character past the end of the line
+/// points to the end of the line, before its terminator, and a line past the end of the text to
+/// the end of the text.
+fn position_to_char(text: &Rope, position: Position, encoding: PositionEncoding) -> usize {
+    if position.line as usize >= text.len_lines() {
+        return text.len_chars();
+    }
+    let line = line_content(text.line(position.line as usize));
+    let line_start = text.line_to_char(position.line as usize);
+    let character = position.character as usize;
+
+    match encoding {
+        PositionEncoding::Utf8 => line_start + line.byte_to_char(character.min(line.len_bytes())),
+        PositionEncoding::Utf16 => {
+            line_start + line.utf16_cu_to_char(character.min(line.len_utf16_cu()))
+        }
+    }
+}
+
+/// What applying edits changed.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct EditSummary {
+    /// How many edits were applied, overlapping and out of bounds ones are skipped.
+    pub(crate) edits: usize,
+    /// How many bytes the text grew by, negative when it shrank.
+    pub(crate) bytes_added: isize,
+}
+
+/// Applies the `edits` the server sent for `text`, whose ranges may span several lines and refer
+/// to the text as it was before any edit.
+pub(crate) fn apply_edits(
+    text: &mut Rope,
+    edits: &[TextEdit],
+    encoding: PositionEncoding,
+) -> EditSummary {
+    // the ranges refer to the original text, so apply them back-to-front to keep the positions
+    // of the remaining edits valid
+    let mut edits = edits.iter().collect::<Vec<_>>();
+    // an insertion sorts before a replacement starting at the same position, so both are applied
+    // whatever order the server sent them in
+    edits.sort_by_key(|edit| (edit.range.start, edit.range.end));
+
+    let mut summary = EditSummary::default();
+    let mut next_start: Option<Position> = None;
+    for edit in edits.into_iter().rev() {
+        // the spec forbids overlapping edits, but don't trust the server on that
+        if next_start.is_some_and(|next_start| edit.range.end > next_start) {
+            tracing::warn!("skipping overlapping edit at {:?}", edit.range);
+            continue;
+        }
+        // ropey panics on ranges outside of the text, which buggy formatters may send
+        if edit.range.start.line as usize >= text.len_lines() || edit.range.start > edit.range.end {
+            tracing::warn!("skipping out of bounds edit at {:?}", edit.range);
+            continue;
+        }
+        next_start = Some(edit.range.start);
+
+        let start = position_to_char(text, edit.range.start, encoding);
+        let end = position_to_char(text, edit.range.end, encoding);
+
+        let removed = text.slice(start..end).len_bytes();
+        text.remove(start..end);
+        text.insert(start, &edit.new_text);
+
+        summary.edits += 1;
+        summary.bytes_added += edit.new_text.len() as isize - removed as isize;
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::Range;
+
+    use super::*;
+
+    fn edit(start: (u32, u32), end: (u32, u32), new_text: &str) -> TextEdit {
+        TextEdit {
+            range: Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1)),
+            new_text: new_text.into(),
+        }
+    }
+
+    fn applied(text: &str, edits: &[TextEdit], encoding: PositionEncoding) -> String {
+        let mut rope = Rope::from_str(text);
+        apply_edits(&mut rope, edits, encoding);
+        rope.to_string()
+    }
+
+    #[test]
+    fn edits_after_multi_byte_characters() {
+        // `é` is one UTF-16 code unit but two UTF-8 bytes, and `𝕏` two code units and four bytes
+        let text = "let é𝕏 = 1\n";
+        let edits = [edit((0, 8), (0, 11), "= 2")];
+        assert_eq!(
+            applied(text, &edits, PositionEncoding::Utf16),
+            "let é𝕏 = 2\n"
+        );
+    }
+
+    #[test]
+    fn edits_on_one_line_refer_to_the_original_text() {
+        let text = "val x:int\n";
+        let edits = [edit((0, 5), (0, 5), " "), edit((0, 6), (0, 9), " int")];
+        assert_eq!(
+            applied(text, &edits, PositionEncoding::Utf16),
+            "val x : int\n"
+        );
+    }
+
+    #[test]
+    fn insertion_and_replacement_at_one_position() {
+        let text = "val x : t\n";
+        let replace = edit((0, 8), (0, 9), "int");
+        let insert = edit((0, 8), (0, 8), "(* y *) ");
+        for edits in [[replace.clone(), insert.clone()], [insert, replace]] {
+            assert_eq!(
+                applied(text, &edits, PositionEncoding::Utf16),
+                "val x : (* y *) int\n"
+            );
+        }
+    }
+
+    #[test]
+    fn utf8_positions_count_bytes() {
+        let text = "let café = \"☕\"\n";
+        let edits = [edit((0, 12), (0, 17), "\"tea\"")];
+        assert_eq!(
+            applied(text, &edits, PositionEncoding::Utf8),
+            "let café = \"tea\"\n"
+        );
+    }
+
+    #[test]
+    fn utf16_positions_count_code_units() {
+        let text = "let café = \"☕\"\n";
+        let edits = [edit((0, 11), (0, 14), "\"tea\"")];
+        assert_eq!(
+            applied(text, &edits, PositionEncoding::Utf16),
+            "let café = \"tea\"\n"
+        );
+    }
+
+    #[test]
+    fn keeps_crlf_terminators() {
+        let text = "val x  :  int\r\nval y : int\r\n";
+        // a character past the end of the line stops before its `\r\n`
+        let edits = [edit((0, 0), (0, 99), "val x : int")];
+        assert_eq!(
+            applied(text, &edits, PositionEncoding::Utf16),
+            "val x : int\r\nval y : int\r\n"
+        );
+    }
+
+    #[test]
+    fn clamps_ends_past_the_end_of_the_text() {
+        let text = "val x : int\n";
+        let edits = [edit((0, 8), (9, 0), "bool\n")];
+        assert_eq!(
+            applied(text, &edits, PositionEncoding::Utf16),
+            "val x : bool\n"
+        );
+    }
+
+    #[test]
+    fn skips_out_of_bounds_edits() {
+        let text = "val x : int\n";
+        for edit in [
+            edit((5, 0), (5, 0), "val y : int\n"),
+            edit((0, 8), (0, 4), ""),
+        ] {
+            let mut rope = Rope::from_str(text);
+            let summary = apply_edits(&mut rope, &[edit], PositionEncoding::Utf16);
+            assert_eq!(summary.edits, 0);
+            assert_eq!(rope, text);
+        }
+    }
+
+    #[test]
+    fn replaces_ranges_spanning_several_lines() {
+        let text = "val a : int\nval b : int\nval c : int\ntype t =\n  | A\n  | B\n  | C\nval d : int\nval e : int\n";
+        let edits = [edit((3, 5), (7, 3), "t = A | B | C\nval")];
+        assert_eq!(
+            applied(text, &edits, PositionEncoding::Utf16),
+            "val a : int\nval b : int\nval c : int\ntype t = A | B | C\nval d : int\nval e : int\n"
+        );
+    }
+}
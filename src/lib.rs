@@ -0,0 +1,313 @@
+//! Infer a `.mli` file from a `.ml` file using the OCaml Language Server.
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use encoding_rs::Encoding;
+use lsp_types::{Diagnostic, DiagnosticSeverity};
+
+pub use async_lsp;
+pub use encoding_rs;
+pub use lsp_types;
+
+pub use crate::config::{InferConfig, InferConfigBuilder};
+pub use crate::edits::PositionEncoding;
+pub use crate::ocamlformat::nearest_ocamlformat;
+pub use crate::session::{request_infer_intf, Capabilities, InferSession};
+
+mod config;
+mod docs;
+mod edits;
+mod ocamlformat;
+mod select;
+mod session;
+
+/// The inferred interface of a file, along with the diagnostics the server published for the
+/// file while inferring it.
+#[derive(Debug, Clone)]
+pub struct Inference {
+    pub interface: String,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Whether the server formatted the interface.
+    pub formatted: bool,
+    pub timings: Timings,
+}
+
+/// How long inferring an interface took, by phase.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    /// Inferring the interface, retries included.
+    pub infer: Duration,
+    /// Formatting the interface, zero when it wasn't formatted.
+    pub format: Duration,
+}
+
+/// How long starting the language server took, by phase.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StartupTimings {
+    pub spawn: Duration,
+    pub initialize: Duration,
+    /// Waiting for the server to index the project.
+    pub index: Duration,
+}
+
+impl Inference {
+    /// Whether the source file has errors, in which case the interface is likely incomplete.
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Some(DiagnosticSeverity::ERROR))
+    }
+}
+
+/// An interface to be written to a file, and what became of it.
+#[derive(Debug, Clone)]
+pub struct InferOutcome {
+    pub path: PathBuf,
+    pub content: String,
+    /// `content` as it's written to `path`.
+    pub bytes: Vec<u8>,
+    /// Whether `content` was written to `path`.
+    pub wrote: bool,
+    /// Whether `path` already holds `content`, in which case it's never written.
+    pub unchanged: bool,
+}
+
+impl InferOutcome {
+    /// The outcome of `content` to be written to `path` in `encoding`, before it's written.
+    /// Fails when `content` can't be written in `encoding`.
+    pub fn new(
+        path: impl Into<PathBuf>,
+        content: String,
+        encoding: &'static Encoding,
+    ) -> color_eyre::Result<Self> {
+        let path = path.into();
+        let (bytes, _, unmappable) = encoding.encode(&content);
+        if unmappable {
+            return Err(eyre!(
+                "the interface of {} can't be written in {}",
+                path.display(),
+                encoding.name()
+            ));
+        }
+        let bytes = bytes.into_owned();
+        let unchanged = std::fs::read(&path).is_ok_and(|current| current == bytes);
+        Ok(Self {
+            path,
+            content,
+            bytes,
+            wrote: false,
+            unchanged,
+        })
+    }
+
+    /// Writes the content to the file unless it's unchanged, as rewriting the same interface
+    /// would only bump its mtime and trigger rebuilds.
+    pub fn write(&mut self) -> color_eyre::Result<()> {
+        if !self.unchanged {
+            std::fs::write(&self.path, &self.bytes).wrap_err("couldn't write file")?;
+            self.wrote = true;
+        }
+        Ok(())
+    }
+}
+
+/// How the interface already written compares to the inferred one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MliStatus {
+    UpToDate,
+    OutOfDate,
+    /// There's no interface, or it can't be read.
+    Missing,
+}
+
+impl MliStatus {
+    /// Compares the interface at `path`, decoded from `encoding`, with `content`. The `header`
+    /// it starts with is ignored, so adding a header doesn't make it out of date.
+    pub fn of(path: &Path, content: &str, header: &str, encoding: &'static Encoding) -> Self {
+        match read_text(path, encoding) {
+            Ok(current) if current.strip_prefix(header).unwrap_or(&current) == content => {
+                Self::UpToDate
+            }
+            Ok(_) => Self::OutOfDate,
+            Err(_) => Self::Missing,
+        }
+    }
+}
+
+/// The command used to spawn the language server.
+#[derive(Debug, Clone)]
+pub struct ServerCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    pub stderr: ServerStderr,
+    /// Settings sent to the server once it's initialized, their schema is up to the server.
+    pub settings: Option<serde_json::Value>,
+}
+
+impl Default for ServerCommand {
+    fn default() -> Self {
+        Self {
+            program: "ocamllsp".into(),
+            args: Vec::new(),
+            stderr: ServerStderr::default(),
+            settings: None,
+        }
+    }
+}
+
+/// Where the error output of the language server goes.
+#[derive(Debug, Clone, Default)]
+pub enum ServerStderr {
+    /// The error output of this process.
+    #[default]
+    Inherit,
+    /// Nowhere.
+    Null,
+    /// Appended to a file, so servers started one after the other don't overwrite each other.
+    File(PathBuf),
+}
+
+/// Error returned when the language server doesn't answer a request in time.
+#[derive(Debug)]
+pub struct TimedOut {
+    pub phase: &'static str,
+    pub after: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} timed out after {}s",
+            self.phase,
+            self.after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Error returned when the language server exits in the middle of a session.
+#[derive(Debug)]
+pub struct ServerExited {
+    pub program: String,
+    pub status: ExitStatus,
+}
+
+impl fmt::Display for ServerExited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} exited unexpectedly ({}), its error output should tell why",
+            self.program, self.status
+        )
+    }
+}
+
+impl std::error::Error for ServerExited {}
+
+/// The interface file of the implementation `file`, a `.rei` for Reason files and a `.mli`
+/// otherwise.
+pub fn interface_path(file: &Path) -> PathBuf {
+    match file.extension().and_then(OsStr::to_str) {
+        Some("re") => file.with_extension("rei"),
+        _ => file.with_extension("mli"),
+    }
+}
+
+/// Reads `path`, decoding it from `encoding`, which fails with [`std::io::ErrorKind::InvalidData`]
+/// when it isn't valid in it.
+pub fn read_text(path: &Path, encoding: &'static Encoding) -> std::io::Result<String> {
+    let bytes = std::fs::read(path)?;
+    encoding
+        .decode_without_bom_handling_and_without_replacement(&bytes)
+        .map(Cow::into_owned)
+        .ok_or_else(|| {
+            let message = format!("it isn't valid {}", encoding.name());
+            std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+        })
+}
+
+/// Files marking the root of a project, by priority.
+const PROJECT_MARKERS: &[&str] = &["dune-project", ".git"];
+
+/// Finds the root of the project `file` belongs to, the nearest ancestor containing a
+/// `dune-project`, or a `.git` when there's none, like `ocamllsp` does. Falls back to the parent
+/// directory of `file` when no marker is found. A directory is its own nearest ancestor.
+pub fn find_project_root(file: &Path) -> PathBuf {
+    let parent = if file.is_dir() {
+        file
+    } else {
+        file.parent().unwrap_or(file)
+    };
+    PROJECT_MARKERS
+        .iter()
+        .find_map(|marker| parent.ancestors().find(|dir| dir.join(marker).exists()))
+        .unwrap_or(parent)
+        .to_path_buf()
+}
+
+/// Infers the interface of `src`, to be written to `dest`, by spawning the language server in
+/// `root_dir` which both are resolved against, as configured by `config`. The interface is
+/// returned without writing anything to disk.
+///
+/// Every request to the server fails with [`TimedOut`] when it takes longer than the configured
+/// timeout. The doc comments of the interface already at `dest` are carried over unless
+/// configured otherwise.
+pub async fn infer_mli(
+    root_dir: &Path,
+    src: &Path,
+    dest: &Path,
+    config: &InferConfig,
+) -> color_eyre::Result<String> {
+    let mut results = infer_mlis(root_dir, &[(src, dest)], config).await?;
+    results.remove(0).map(|inference| inference.interface)
+}
+
+/// Infers the interface of `src` like [`infer_mli`], and writes it to `dest` when `write` is
+/// true and it changed. The interface is returned either way, so `write` can be false to only
+/// find out whether it would change.
+pub async fn write_mli(
+    root_dir: &Path,
+    src: &Path,
+    dest: &Path,
+    config: &InferConfig,
+    write: bool,
+) -> color_eyre::Result<InferOutcome> {
+    let content = infer_mli(root_dir, src, dest, config).await?;
+    let mut outcome = InferOutcome::new(root_dir.join(dest), content, config.encoding)?;
+    if write {
+        outcome.write()?;
+    }
+    Ok(outcome)
+}
+
+/// Infers the interfaces of several files with a single [`InferSession`], so the project is
+/// only indexed once. `files` are pairs of the source and destination of each interface, as for
+/// [`infer_mli`]. A failure on one file doesn't stop the others, the results are returned in the
+/// same order as `files`.
+pub async fn infer_mlis(
+    root_dir: &Path,
+    files: &[(&Path, &Path)],
+    config: &InferConfig,
+) -> color_eyre::Result<Vec<color_eyre::Result<Inference>>> {
+    let mut session = InferSession::start(root_dir, config).await?;
+
+    let mut results = Vec::with_capacity(files.len());
+    for (src, dest) in files {
+        results.push(session.infer(src, dest).await);
+    }
+
+    // the interfaces are already inferred at this point, so don't throw them away
+    if let Err(err) = session.shutdown().await {
+        tracing::warn!("{err:#}");
+    }
+
+    Ok(results)
+}
@@ -1,224 +1,2087 @@
-use std::fs::read_to_string;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::io::{IsTerminal, Read, Write};
 use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
-use std::process::Stdio;
-
-use async_lsp::concurrency::ConcurrencyLayer;
-use async_lsp::panic::CatchUnwindLayer;
-use async_lsp::router::Router;
-use async_lsp::{LanguageServer, ServerSocket};
-use clap::Parser;
-use color_eyre::eyre::Context;
-use futures::channel::oneshot;
-use lsp_types::notification::{LogMessage, Progress, PublishDiagnostics, ShowMessage};
-use lsp_types::request::Request;
-use lsp_types::{
-    ClientCapabilities, DidOpenTextDocumentParams, DocumentFormattingParams, InitializeParams,
-    InitializedParams, NumberOrString, ProgressParamsValue, TextDocumentItem, TextEdit, Url,
-    WindowClientCapabilities, WorkDoneProgress, WorkspaceFolder,
+use std::process::{Output, Stdio};
+use std::time::{Duration, Instant};
+
+use clap::{CommandFactory, Parser};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Report;
+use encoding_rs::Encoding;
+use futures::future::join_all;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use infer_mli::{
+    find_project_root, interface_path, nearest_ocamlformat, read_text, InferConfig, InferOutcome,
+    InferSession, Inference, MliStatus, PositionEncoding, ServerCommand, ServerStderr, TimedOut,
+    Timings,
 };
-use ropey::Rope;
-use tower::ServiceBuilder;
+use lsp_types::{Diagnostic, DiagnosticSeverity, FormattingOptions, ServerInfo, Url};
+use notify::{EventKind, RecursiveMode, Watcher};
+use similar::TextDiff;
+use tokio::io::AsyncBufReadExt;
+use tokio::time::timeout;
 use tracing::Level;
 
-struct ClientState {
-    indexed_tx: Option<oneshot::Sender<()>>,
+/// How long to wait for more changes before inferring a changed file again, editors often write
+/// a file several times when saving it.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+const EXIT_STATUS: &str = "\
+Exit status:
+  0  every interface was inferred
+  1  inferring or writing an interface failed, or --check found a difference
+  2  invalid arguments
+  3  the language server couldn't be started
+  4  a source file has errors, with --fail-on-error
+  5  the language server timed out
+  130  interrupted by Ctrl-C
+
+When several files fail, the exit status is the one of the first failure.";
+
+/// Why a run failed, the discriminant being the exit status. See [`EXIT_STATUS`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    Inference = 1,
+    /// The same status clap exits with on invalid arguments.
+    Usage = 2,
+    Spawn = 3,
+    TypeErrors = 4,
+    Timeout = 5,
+    /// The status shells give to processes killed by SIGINT.
+    Interrupted = 130,
+}
+
+impl Failure {
+    /// The failure of a file that couldn't be inferred.
+    fn of(err: &Report) -> Self {
+        if err.downcast_ref::<TimedOut>().is_some() {
+            Self::Timeout
+        } else {
+            Self::Inference
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Print the completion script of a shell
+    #[command(hide = true)]
+    Completions { shell: clap_complete::Shell },
+    /// Check that the language server can be found, started and infer interfaces
+    Doctor {
+        #[command(flatten)]
+        server: ServerArgs,
+    },
+}
+
+/// The language server to run, shared by the inference and `doctor`.
+#[derive(clap::Args)]
+struct ServerArgs {
+    /// The language server binary to run
+    #[clap(long, default_value = "ocamllsp")]
+    ocamllsp_path: String,
+
+    /// An extra argument to pass to the language server, can be repeated
+    #[clap(long, allow_hyphen_values = true)]
+    ocamllsp_arg: Vec<String>,
+}
+
+/// How the results are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum OutputFormat {
+    /// The paths of the written files, or the interfaces with --stdout
+    #[default]
+    Text,
+    /// An object per file, or an array of them when inferring several files
+    Json,
+}
+
+/// The encoding of the positions sent by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum PositionEncodingArg {
+    /// The one negotiated with the server
+    #[default]
+    Auto,
+    Utf8,
+    Utf16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+enum RootUriArg {
+    /// When there are no other workspace folders
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// An unsaved buffer to infer the interface of, as read with `--request`.
+#[derive(serde::Deserialize)]
+struct BufferRequest {
+    path: String,
+    text: String,
+    version: i32,
+}
+
+/// A request read with `--serve`, as `{ "file": ..., "options": { ... } }`.
+#[derive(serde::Deserialize)]
+struct ServeRequest {
+    file: String,
+    #[serde(default)]
+    options: ServeOptions,
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default)]
+struct ServeOptions {
+    /// The content of an unsaved buffer to infer instead of the content of the file.
+    text: Option<String>,
+    /// Whether the interface is written to its .mli file too.
+    write: bool,
+}
+
+/// The answer to a [`ServeRequest`], either the interface or the error inferring it.
+#[derive(Default, serde::Serialize)]
+struct ServeResponse {
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interface: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    diagnostics: Vec<Diagnostic>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A processed file, as printed with `--format json`.
+#[derive(serde::Serialize)]
+struct Record {
+    source: PathBuf,
+    mli: PathBuf,
+    interface: String,
+    formatted: bool,
+    diagnostics: Vec<Diagnostic>,
+    #[serde(skip)]
+    outcome: Outcome,
+}
+
+/// What became of the interface of a processed file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Written,
+    Unchanged,
+    /// Checked, diffed, printed or not written because of --dry-run.
+    Reported,
+}
+
+/// The hashes of the sources whose interface is up to date, as stored with `--cache`.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Cache {
+    /// The hash of everything else the interfaces depend on, like the version of the server, the
+    /// entries are only valid for the same key.
+    key: String,
+    files: BTreeMap<PathBuf, CacheEntry>,
+}
+
+#[derive(Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct CacheEntry {
+    source: String,
+    interface: String,
+}
+
+impl Cache {
+    /// The cache stored at `path`, with no entries when it doesn't exist, can't be read or was
+    /// made with another `key`.
+    fn load(path: &Path, key: String) -> Self {
+        let cache = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|cache| serde_json::from_str::<Cache>(&cache).ok())
+            .filter(|cache| cache.key == key);
+        cache.unwrap_or(Cache {
+            key,
+            files: BTreeMap::new(),
+        })
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, format!("{json}\n"))
+    }
+
+    /// The entry of `source` with its interface at `mli`, if they can be read.
+    fn entry(source: &Path, mli: &Path) -> Option<CacheEntry> {
+        Some(CacheEntry {
+            source: hash(&std::fs::read(source).ok()?),
+            interface: hash(&std::fs::read(mli).ok()?),
+        })
+    }
+
+    /// Whether neither `source` nor its interface at `mli` changed since they were cached.
+    fn is_fresh(&self, source: &Path, mli: &Path) -> bool {
+        self.files
+            .get(source)
+            .is_some_and(|entry| Self::entry(source, mli).as_ref() == Some(entry))
+    }
 }
 
-struct Stop;
+/// The hash of everything the interfaces of `files` depend on besides their source, so the
+/// cache is invalidated when it changes. Options that don't change the interfaces, like the
+/// timeouts, are left out so changing them keeps the cache.
+fn cache_key(
+    args: &Args,
+    config: &InferConfig,
+    root_dir: &Path,
+    files: &[PathBuf],
+) -> Option<String> {
+    let server = &config.server;
+    let version = server_version(&server.program, &server.args).ok()?;
+    // the sets and maps of the config in order, so the key is the same from one run to the next
+    let only = config.only.as_ref().map(BTreeSet::from_iter);
+    let formatting = config.formatting.clone().map(|mut formatting| {
+        let properties = BTreeMap::from_iter(std::mem::take(&mut formatting.properties));
+        (formatting, properties)
+    });
+    let ocamlformats = if config.ocamlformat {
+        files
+            .iter()
+            .filter_map(|file| nearest_ocamlformat(root_dir.join(file).parent()?, root_dir))
+            .map(|path| {
+                let options = std::fs::read(&path).unwrap_or_default();
+                (path, options)
+            })
+            .collect()
+    } else {
+        BTreeMap::new()
+    };
+    let key =
+        format!(
+        "{} {:?} {:?} {:?} {:?} {:?} {formatting:?} {only:?} {:?} {:?} {ocamlformats:?} {:?} {:?}",
+        env!("CARGO_PKG_VERSION"),
+        version.stdout,
+        (&server.program, &server.args, &server.settings),
+        (&config.workspace_folders, config.root_uri),
+        (&config.language_id, &config.uri_template, config.save_before_infer),
+        (config.position_encoding, config.encoding.name()),
+        args.header,
+        args.post_process,
+        (config.merge_docs, config.final_newline, config.append_to_existing),
+        (config.strip_includes, config.strip_opens),
+    );
+    Some(hash(key.as_bytes()))
+}
 
-struct InferIntf;
+/// The 64-bit FNV-1a hash of `bytes`, which unlike the hashers of the standard library is the
+/// same from one build to the next, as the cache is kept on disk.
+fn hash(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf29ce484222325_u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
 
-impl Request for InferIntf {
-    type Params = Vec<Url>;
-    type Result = String;
-    const METHOD: &'static str = "ocamllsp/inferIntf";
+/// The totals of a run, as written with `--summary-json`.
+#[derive(serde::Serialize)]
+struct Summary {
+    processed: usize,
+    written: Vec<PathBuf>,
+    unchanged: Vec<PathBuf>,
+    failed: Vec<PathBuf>,
+    skipped: Vec<PathBuf>,
+    /// In seconds.
+    duration: f64,
 }
 
 #[derive(clap::Parser)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_long_help = EXIT_STATUS,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    #[clap(short, long)]
-    root_dir: String,
+    #[command(subcommand)]
+    command: Option<Command>,
 
+    /// The project root, detected from the first file when not given. Files are relative to it
+    /// when it's given, and to the current directory otherwise
     #[clap(short, long)]
-    file: String,
+    root_dir: Option<String>,
+
+    /// The files to infer the interfaces of, `-` reads the source from stdin like --stdin. The
+    /// sources of directories are inferred, except for those under `_build` or hidden directories
+    #[clap(
+        short,
+        long,
+        num_args = 1..,
+        required_unless_present_any = ["files_from", "stdin", "request", "serve"]
+    )]
+    file: Vec<String>,
+
+    /// Infer the interface of an unsaved buffer described by this JSON file, or stdin when it's
+    /// `-`, as `{ "path": ..., "text": ..., "version": ... }`, and print it to stdout. The path is
+    /// resolved like the files
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["file", "files_from", "stdin", "check", "diff", "watch", "output", "out_dir"]
+    )]
+    request: Option<String>,
+
+    /// Read the source from stdin and print its interface to stdout
+    #[clap(long, conflicts_with_all = ["check", "diff", "watch", "output", "out_dir"])]
+    stdin: bool,
+
+    /// Read more files from this file, one per line, or from stdin when it's `-`. They're
+    /// relative to the same directory as the other files
+    #[clap(long)]
+    files_from: Option<String>,
+
+    /// Only infer the sources of directories matching this glob, relative to the project root.
+    /// Can be repeated
+    #[clap(long, value_name = "GLOB", value_parser = Glob::new)]
+    include: Vec<Glob>,
+
+    /// Skip the sources of directories matching this glob, relative to the project root. Can be
+    /// repeated
+    #[clap(long, value_name = "GLOB", value_parser = Glob::new)]
+    exclude: Vec<Glob>,
+
+    /// Skip the sources of directories ignored by git
+    #[clap(long)]
+    respect_gitignore: bool,
+
+    /// Print the inferred interface to stdout instead of writing the .mli file
+    #[clap(long)]
+    stdout: bool,
+
+    /// Don't write anything, exit with a non-zero status if the .mli file is missing or differs
+    /// from the inferred interface
+    #[clap(long, conflicts_with = "stdout")]
+    check: bool,
+
+    /// Don't write anything, print a unified diff between the .mli file and the inferred
+    /// interface instead
+    #[clap(long, conflicts_with_all = ["stdout", "check"])]
+    diff: bool,
+
+    /// Don't write anything, exit with a non-zero status and print a unified diff if this file
+    /// differs from the inferred interface, like --check against a golden file kept elsewhere
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["stdout", "check", "diff", "stage"]
+    )]
+    compare_with: Option<PathBuf>,
+
+    /// Another root to give to the language server along with the project root, to resolve
+    /// modules of sibling projects. Can be repeated
+    #[clap(long, value_name = "PATH")]
+    workspace_folder: Vec<PathBuf>,
+
+    #[command(flatten)]
+    server: ServerArgs,
+
+    /// Append the error output of the language server to this file instead of printing it
+    #[clap(long, value_name = "FILE")]
+    server_log: Option<PathBuf>,
+
+    /// Settings to send to the language server once it's initialized, as JSON
+    #[clap(long, value_name = "JSON", value_parser = parse_json)]
+    server_config: Option<serde_json::Value>,
+
+    /// Discard the error output of the language server
+    #[clap(long, conflicts_with = "server_log")]
+    quiet_server: bool,
+
+    /// How many seconds to wait for each request to the language server, 0 to wait forever
+    #[clap(long, default_value_t = 60)]
+    timeout: u64,
+
+    /// How many seconds to wait for the language server to index the project before inferring
+    /// anyway, 0 to wait forever
+    #[clap(long, default_value_t = 30)]
+    index_timeout: u64,
+
+    /// How many seconds to wait for the language server to format an interface before leaving
+    /// it unformatted, 0 to wait forever
+    #[clap(long, default_value_t = 60)]
+    format_timeout: u64,
+
+    /// How many seconds to wait for the language server to exit once it's shut down, or it
+    /// timed out, before killing it
+    #[clap(long, value_name = "SECONDS", default_value_t = 2)]
+    server_timeout_kill: u64,
+
+    /// Rename an existing .mli file to .mli.bak before overwriting it
+    #[clap(long, conflicts_with_all = ["stdout", "check", "diff"])]
+    backup: bool,
+
+    /// Only keep the declarations named in this file, one per line, along with the types and
+    /// modules they refer to
+    #[clap(long, value_name = "NAMES_FILE")]
+    only: Option<String>,
+
+    /// Only print the declarations named this, along with the types and modules they refer to,
+    /// without writing anything. Can be repeated
+    #[clap(
+        long,
+        value_name = "NAME",
+        conflicts_with_all = ["only", "check", "diff", "output", "out_dir", "watch"]
+    )]
+    print_only: Vec<String>,
+
+    /// Tell the server the sources were saved once they're opened, for servers that only compute
+    /// some of what they know about a file on save
+    #[clap(long)]
+    save_before_infer: bool,
+
+    /// Don't send the options of the nearest .ocamlformat to the language server
+    #[clap(long)]
+    no_ocamlformat: bool,
+
+    /// Only append the declarations missing from the existing .mli file to it, leaving what's
+    /// already there untouched, instead of replacing it with the inferred interface
+    #[clap(long)]
+    append_to_existing: bool,
+
+    /// Remove the top level `include`s from the inferred interface, so it doesn't expose
+    /// everything they include
+    #[clap(long)]
+    strip_includes: bool,
+
+    /// Remove the top level `open`s from the inferred interface
+    #[clap(long)]
+    strip_opens: bool,
+
+    /// The encoding of the sources and interfaces, like latin1 for legacy files
+    #[clap(long, value_name = "NAME", default_value = "utf-8", value_parser = parse_encoding)]
+    encoding: &'static Encoding,
+
+    /// Don't end the interfaces with a newline
+    #[clap(long)]
+    no_final_newline: bool,
+
+    /// A shell command the interfaces are piped through, its output being written instead
+    #[clap(long, value_name = "COMMAND")]
+    post_process: Option<String>,
+
+    /// Refuse sources larger than this many bytes
+    #[clap(long, value_name = "BYTES", default_value_t = 5 * 1024 * 1024)]
+    max_size: u64,
+
+    /// Infer sources whatever their size
+    #[clap(long, conflicts_with = "max_size")]
+    force: bool,
+
+    /// Where to store the source read from stdin, the system's temporary directory by default
+    #[clap(long, value_name = "PATH")]
+    temp_dir: Option<PathBuf>,
+
+    /// Fail instead of creating the missing directories of the written files
+    #[clap(long)]
+    no_create_dirs: bool,
+
+    /// Don't write anything, print what would be written instead
+    #[clap(long, conflicts_with_all = ["stdout", "check", "diff", "compare_with"])]
+    dry_run: bool,
+
+    /// A comment to put at the top of the interfaces, `{source}` being replaced by the name of
+    /// the source file. It's ignored by --check
+    #[clap(long)]
+    header: Option<String>,
+
+    /// Keep inferring the other files after one failed, instead of stopping at the first failure,
+    /// and tell how many succeeded at the end
+    #[clap(long)]
+    keep_going: bool,
+
+    /// Don't write the .mli file and exit with a non-zero status if the source has errors
+    #[clap(long)]
+    fail_on_error: bool,
+
+    /// Keep the language server running and infer the interfaces asked for on stdin, one JSON
+    /// request per line as `{ "file": ..., "options": { "text": ..., "write": ... } }`, each
+    /// answered with one JSON line on stdout. The server is shut down once stdin is closed
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "file",
+            "files_from",
+            "stdin",
+            "request",
+            "watch",
+            "stdout",
+            "check",
+            "diff",
+            "compare_with",
+            "dry_run",
+            "cache",
+            "stage",
+            "print_only",
+            "post_process",
+            "fail_on_error",
+            "fail_if_empty",
+            "backup"
+        ]
+    )]
+    serve: bool,
+
+    /// Stage the interfaces with `git add` once they're written
+    #[clap(
+        long,
+        conflicts_with_all = ["stdout", "check", "diff", "dry_run", "print_only", "stdin", "request"]
+    )]
+    stage: bool,
+
+    /// Fail instead of writing an empty interface, which usually means something went wrong
+    #[clap(long)]
+    fail_if_empty: bool,
+
+    /// Print how long each phase took
+    #[clap(long)]
+    time: bool,
+
+    /// Print which server was started and what it supports
+    #[clap(long)]
+    print_server_info: bool,
+
+    /// Remember the hashes of the sources and interfaces in this file, to skip the sources that
+    /// didn't change since their interface was last written or checked
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "watch",
+            "stdin",
+            "request",
+            "stdout",
+            "diff",
+            "print_only",
+            "compare_with"
+        ]
+    )]
+    cache: Option<PathBuf>,
+
+    /// Write the totals of the run to this file as JSON, the files sorted so runs on the same
+    /// sources can be diffed
+    #[clap(long, value_name = "PATH")]
+    summary_json: Option<PathBuf>,
+
+    /// Log more, can be repeated to log even more
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only log warnings and errors
+    #[clap(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log JSON lines instead of text
+    #[clap(long)]
+    log_json: bool,
+
+    /// The size of an indentation level when formatting
+    #[clap(long, default_value_t = 2)]
+    tab_size: u32,
+
+    /// Indent with tabs instead of spaces when formatting
+    #[clap(long)]
+    use_tabs: bool,
+
+    /// Write the interface as the language server inferred it, without formatting it
+    #[clap(
+        long,
+        conflicts_with_all = ["format_timeout", "tab_size", "use_tabs", "no_ocamlformat"]
+    )]
+    no_format: bool,
+
+    /// Don't carry the doc comments of the existing .mli file over to the inferred interface
+    #[clap(long)]
+    no_merge_docs: bool,
+
+    /// How many times to infer an empty interface again, the server may not be done indexing
+    #[clap(long, default_value_t = 1)]
+    retries: u32,
+
+    /// How many language servers to run at once, each one inferring its share of the files. It's
+    /// at most the number of CPUs
+    #[clap(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    /// The language the files are opened as, `reason` for .re files and `ocaml` otherwise by
+    /// default
+    #[clap(long)]
+    language_id: Option<String>,
+
+    /// How to count the characters of the positions sent by the server, overriding the
+    /// negotiated encoding. Only useful with servers that don't use the encoding they agreed to
+    #[clap(long, value_enum, default_value_t, hide_short_help = true)]
+    position_encoding: PositionEncodingArg,
+
+    /// When to also send the project root as the deprecated `rootUri` and `rootPath`, which some
+    /// servers still detect the project from
+    #[clap(long, value_enum, default_value_t, hide_short_help = true)]
+    root_uri: RootUriArg,
+
+    /// The template of the URLs sent to the language server instead of `file://` ones, for
+    /// virtual or remote workspaces, `{path}` being replaced by the absolute path of the
+    /// document, e.g. `vscode-vfs://github{path}`
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        value_parser = parse_uri_template,
+        hide_short_help = true
+    )]
+    uri_template: Option<String>,
+
+    /// Write the interface to this path instead of next to the source file, it must be a
+    /// directory when inferring several files
+    #[clap(short, long, conflicts_with = "stdout")]
+    output: Option<String>,
+
+    /// Write the interfaces under this directory instead, at the same path relative to it as
+    /// their source relative to the project root
+    #[clap(long, value_name = "DIR", conflicts_with_all = ["output", "stdout"])]
+    out_dir: Option<PathBuf>,
+
+    /// Keep running after inferring the interfaces, and infer them again whenever the files
+    /// change
+    #[clap(long)]
+    watch: bool,
+
+    /// Print the paths relative to the project root rather than absolute
+    #[clap(long)]
+    relative_paths: bool,
+
+    /// How to print the results
+    #[clap(
+        long = "format",
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t,
+        conflicts_with_all = ["check", "diff", "compare_with"]
+    )]
+    output_format: OutputFormat,
 }
 
-async fn infer_intf(socket: &mut ServerSocket, file: &mut PathBuf) -> color_eyre::Result<String> {
-    let url = Url::from_file_path(file.clone()).expect("file should be valid");
-    let text = socket
-        .request::<InferIntf>(vec![url])
-        .await
-        .wrap_err("couldn't infer interface")?;
+impl Args {
+    fn formatting_options(&self) -> FormattingOptions {
+        FormattingOptions {
+            tab_size: self.tab_size,
+            insert_spaces: !self.use_tabs,
+            trim_trailing_whitespace: Some(true),
+            insert_final_newline: Some(true),
+            ..FormattingOptions::default()
+        }
+    }
+
+    /// How the sessions infer the interfaces.
+    fn config(&self) -> InferConfig {
+        let seconds = |seconds| (seconds > 0).then(|| Duration::from_secs(seconds));
+        let server = ServerCommand {
+            program: self.server.ocamllsp_path.clone(),
+            args: self.server.ocamllsp_arg.clone(),
+            stderr: match (&self.server_log, self.quiet_server) {
+                (Some(path), _) => ServerStderr::File(path.clone()),
+                (None, true) => ServerStderr::Null,
+                (None, false) => ServerStderr::Inherit,
+            },
+            settings: self.server_config.clone(),
+        };
 
-    file.set_extension("mli");
+        let mut config = InferConfig::builder()
+            .server(server)
+            .timeout(seconds(self.timeout))
+            .index_timeout(seconds(self.index_timeout))
+            .format_timeout(seconds(self.format_timeout))
+            .kill_timeout(Duration::from_secs(self.server_timeout_kill))
+            .formatting(self.formatting_options())
+            .no_format(self.no_format)
+            .merge_docs(!self.no_merge_docs)
+            .retries(self.retries)
+            .max_size((!self.force).then_some(self.max_size))
+            .final_newline(!self.no_final_newline)
+            .save_before_infer(self.save_before_infer)
+            .encoding(self.encoding)
+            .ocamlformat(!self.no_ocamlformat)
+            .append_to_existing(self.append_to_existing)
+            .strip_includes(self.strip_includes)
+            .strip_opens(self.strip_opens)
+            .position_encoding(match self.position_encoding {
+                PositionEncodingArg::Auto => None,
+                PositionEncodingArg::Utf8 => Some(PositionEncoding::Utf8),
+                PositionEncodingArg::Utf16 => Some(PositionEncoding::Utf16),
+            })
+            .root_uri(match self.root_uri {
+                RootUriArg::Auto => None,
+                RootUriArg::Always => Some(true),
+                RootUriArg::Never => Some(false),
+            });
+        for folder in &self.workspace_folder {
+            config = config.workspace_folder(folder);
+        }
+        if let Some(language_id) = &self.language_id {
+            config = config.language_id(language_id);
+        }
+        if let Some(template) = &self.uri_template {
+            config = config.uri_template(template);
+        }
+        config.build()
+    }
 
-    let mli_url = Url::from_file_path(file.clone()).expect("file should be valid");
+    /// Where the interface of `file` goes.
+    fn mli_path(&self, root_dir: &Path, file: &Path) -> PathBuf {
+        let mut source = root_dir.join(file);
+        // the server inferred the interface of the real source, so that's where it belongs
+        if source.is_symlink() {
+            source = source.canonicalize().unwrap_or(source);
+        }
+        let mli_file = interface_path(&source);
+        match &self.output {
+            // the session resolves relative paths against the root, not the current directory
+            Some(output) => {
+                let output = std::path::absolute(output).unwrap_or_else(|_| output.into());
+                if output.is_dir() {
+                    output.join(mli_file.file_name().unwrap())
+                } else {
+                    output
+                }
+            }
+            None => match &self.out_dir {
+                Some(out_dir) => {
+                    let out_dir = std::path::absolute(out_dir).unwrap_or_else(|_| out_dir.into());
+                    // sources outside of the root go right under it
+                    let relative = mli_file
+                        .strip_prefix(root_dir)
+                        .unwrap_or(Path::new(mli_file.file_name().unwrap()));
+                    out_dir.join(relative)
+                }
+                None => mli_file,
+            },
+        }
+    }
 
-    // open the mli file to be formatted
-    open_file(socket, file.clone(), &text).await?;
+    /// The header comment of the interface of `file`, if there's one.
+    fn header(&self, file: &Path) -> String {
+        let Some(header) = &self.header else {
+            return String::new();
+        };
+        let source = file.file_name().unwrap_or_default().to_string_lossy();
+        format!("(* {} *)\n\n", header.replace("{source}", &source))
+    }
 
-    // format the mli file
-    let format_result = socket
-        .formatting(DocumentFormattingParams {
-            text_document: lsp_types::TextDocumentIdentifier { uri: mli_url },
-            options: Default::default(),
-            work_done_progress_params: Default::default(),
-        })
-        .await;
+    /// `path` as it's printed, relative to the project root with --relative-paths.
+    fn shown<'a>(&self, root_dir: &Path, path: &'a Path) -> &'a Path {
+        if self.relative_paths {
+            path.strip_prefix(root_dir).unwrap_or(path)
+        } else {
+            path
+        }
+    }
+
+    fn log_level(&self) -> Level {
+        match (self.quiet, self.verbose) {
+            (true, _) => Level::WARN,
+            (false, 0) => Level::INFO,
+            (false, 1) => Level::DEBUG,
+            (false, _) => Level::TRACE,
+        }
+    }
+}
+
+/// Parses the JSON of a flag, so invalid JSON is rejected before starting the server.
+fn parse_json(text: &str) -> serde_json::Result<serde_json::Value> {
+    serde_json::from_str(text)
+}
+
+fn print_server_info(session: &InferSession) {
+    match session.server_info() {
+        Some(ServerInfo { name, version }) => {
+            let version = version.as_deref().unwrap_or("unknown version");
+            eprintln!("server: {name} {version}");
+        }
+        None => eprintln!("server: unknown"),
+    }
+    let capabilities = session.capabilities();
+    let supported = |supported: bool| if supported { "yes" } else { "no" };
+    eprintln!("formatting: {}", supported(capabilities.formatting));
+    eprintln!(
+        "position encoding: {}",
+        match capabilities.encoding {
+            PositionEncoding::Utf8 => "utf-8",
+            PositionEncoding::Utf16 => "utf-16",
+        }
+    );
+    eprintln!(
+        "inferring interfaces: {}",
+        capabilities.infer_intf.map_or("not advertised", supported)
+    );
+}
+
+fn print_diagnostics(file: &Path, inference: &Inference) {
+    for diagnostic in &inference.diagnostics {
+        let severity = match diagnostic.severity {
+            Some(DiagnosticSeverity::ERROR) => "error",
+            Some(DiagnosticSeverity::WARNING) => "warning",
+            _ => continue,
+        };
+        let start = diagnostic.range.start;
+        eprintln!(
+            "{}:{}:{}: {severity}: {}",
+            file.display(),
+            start.line + 1,
+            start.character + 1,
+            diagnostic.message
+        );
+    }
+}
+
+/// Pipes `interface` through the shell `command`, returning its output.
+fn post_process(command: &str, interface: &str) -> color_eyre::Result<String> {
+    let mut child = std::process::Command::new("sh")
+        .args(["-c", command])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err_with(|| format!("couldn't run {command}"))?;
+    // written from another thread, so the command doesn't block on a full stdout meanwhile
+    let mut stdin = child.stdin.take().unwrap();
+    let input = interface.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child
+        .wait_with_output()
+        .wrap_err_with(|| format!("couldn't run {command}"))?;
+    // the command may not read all of its input, which isn't an error
+    let _ = writer.join();
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!(
+            "{command} failed ({}): {}",
+            output.status,
+            stderr.trim_end()
+        ));
+    }
+    String::from_utf8(output.stdout).wrap_err_with(|| format!("{command} didn't output UTF-8"))
+}
+
+/// Prints the path of the interface `shown` like a written one, so `$(infer-mli ...)` gets the
+/// path whether it was written or not, and tells it's unchanged on stderr.
+fn print_unchanged(shown: &Path) {
+    println!("{}", shown.display());
+    eprintln!("{} unchanged", shown.display());
+}
+
+/// Creates the missing parent directories of `file`, unless --no-create-dirs is given.
+fn create_parent(args: &Args, file: &Path) -> color_eyre::Result<()> {
+    let Some(parent) = file
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    else {
+        return Ok(());
+    };
+    if parent.is_dir() {
+        return Ok(());
+    }
+    if args.no_create_dirs {
+        return Err(eyre!(
+            "couldn't write {}, {} doesn't exist",
+            file.display(),
+            parent.display()
+        ));
+    }
+    std::fs::create_dir_all(parent)
+        .wrap_err_with(|| format!("couldn't create {}", parent.display()))
+}
+
+/// Finds a name for the backup of `file` that doesn't clobber a previous backup.
+fn backup_path(file: &Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".bak");
+    let mut path = PathBuf::from(&name);
+
+    let mut n = 1;
+    while path.exists() {
+        path = PathBuf::from(format!("{}.{n}", name.to_string_lossy()));
+        n += 1;
+    }
+    path
+}
+
+/// Formats a number of bytes like `1.2KB`.
+fn human_size(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{bytes}B"),
+        1024..1048576 => format!("{:.1}KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1}MB", bytes as f64 / 1048576.0),
+    }
+}
+
+fn human_duration(duration: Duration) -> String {
+    match duration.as_millis() {
+        0..1000 => format!("{}ms", duration.as_millis()),
+        _ => format!("{:.2}s", duration.as_secs_f64()),
+    }
+}
+
+/// The encoding named `label`, as the WHATWG Encoding Standard names them.
+fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding {label}"))
+}
+
+/// Checks that `template` makes valid URLs, so a typo is caught before starting the server.
+fn parse_uri_template(template: &str) -> Result<String, String> {
+    if !template.contains("{path}") {
+        return Err("it must contain {path}".into());
+    }
+    Url::parse(&template.replace("{path}", "/project/src/foo.ml"))
+        .map(|_| template.to_string())
+        .map_err(|err| format!("it doesn't make a valid URL: {err}"))
+}
+
+/// Outputs the interface `text` of `mli_file`, preceded by `header`.
+fn output(
+    args: &Args,
+    root_dir: &Path,
+    mli_file: &Path,
+    header: &str,
+    text: &str,
+) -> color_eyre::Result<Outcome> {
+    let shown = args.shown(root_dir, mli_file);
+    if let Some(golden) = &args.compare_with {
+        let golden_text = read_text(golden, args.encoding)
+            .wrap_err_with(|| format!("couldn't read {}", golden.display()))?;
+        let golden_text = golden_text.strip_prefix(header).unwrap_or(&golden_text);
+        if golden_text == text {
+            return Ok(Outcome::Reported);
+        }
+        let diff = TextDiff::from_lines(golden_text, text);
+        let (old, new) = (golden.to_string_lossy(), shown.to_string_lossy());
+        print!("{}", diff.unified_diff().header(&old, &new));
+        return Err(eyre!(
+            "the interface of {} differs from {}",
+            shown.display(),
+            golden.display()
+        ));
+    }
+    if args.check {
+        // the header doesn't make the file out of date, so adding one doesn't fail the check
+        return match MliStatus::of(mli_file, text, header, args.encoding) {
+            MliStatus::UpToDate => Ok(Outcome::Reported),
+            MliStatus::OutOfDate => Err(eyre!("{} is out of date", shown.to_string_lossy())),
+            MliStatus::Missing => Err(eyre!("{} is missing", shown.to_string_lossy())),
+        };
+    }
 
-    // check if the formatting was successful
-    if let Ok(result) = format_result {
-        let mut rope = Rope::from_str(&text);
-        apply_edits(&mut rope, &result.unwrap_or_default());
-        Ok(rope.to_string())
+    let text = format!("{header}{text}");
+    if args.diff {
+        let current = read_text(mli_file, args.encoding).unwrap_or_default();
+        let path = shown.to_string_lossy();
+        let diff = TextDiff::from_lines(current.as_str(), text.as_str());
+        print!("{}", diff.unified_diff().header(&path, &path));
+        return Ok(Outcome::Reported);
+    }
+    if args.stdout {
+        if args.output_format == OutputFormat::Text {
+            print!("{text}");
+        }
+        return Ok(Outcome::Reported);
+    }
+
+    let mut outcome = InferOutcome::new(mli_file, text, args.encoding)?;
+    if outcome.unchanged {
+        if args.output_format == OutputFormat::Text {
+            print_unchanged(shown);
+        }
+        Ok(Outcome::Unchanged)
+    } else if args.dry_run {
+        if args.output_format == OutputFormat::Text {
+            if args.backup && mli_file.exists() {
+                let backup = backup_path(mli_file);
+                println!("would back up {} to {}", shown.display(), backup.display());
+            }
+            println!(
+                "would write {} to {}",
+                human_size(outcome.bytes.len()),
+                shown.display()
+            );
+        }
+        Ok(Outcome::Reported)
     } else {
-        Ok(text)
+        if args.backup && mli_file.exists() {
+            let backup = backup_path(mli_file);
+            create_parent(args, &backup)?;
+            std::fs::rename(mli_file, &backup)
+                .wrap_err_with(|| format!("couldn't back up {}", shown.display()))?;
+        }
+        create_parent(args, mli_file)?;
+        outcome.write()?;
+        if args.output_format == OutputFormat::Text {
+            println!("{}", shown.to_string_lossy());
+        }
+        Ok(Outcome::Written)
     }
 }
 
-async fn open_file(socket: &mut ServerSocket, file: PathBuf, text: &str) -> color_eyre::Result<()> {
-    let url = Url::from_file_path(file).expect("file should be valid");
-    socket
-        .did_open(DidOpenTextDocumentParams {
-            text_document: TextDocumentItem {
-                uri: url.clone(),
-                language_id: "ocaml".into(),
-                version: 0,
-                text: text.into(),
-            },
-        })
-        .wrap_err("couldn't open file")?;
+/// Starts a language server for the files to infer.
+async fn start_session(
+    root_dir: &Path,
+    config: &InferConfig,
+) -> Result<InferSession, (Failure, Report)> {
+    InferSession::start(root_dir, config).await.map_err(|err| {
+        let failure = match Failure::of(&err) {
+            Failure::Timeout => Failure::Timeout,
+            _ => Failure::Spawn,
+        };
+        (failure, err)
+    })
+}
+
+/// Shuts all the `sessions` down at once, only warning when one doesn't shut down cleanly as
+/// whatever they inferred is already done.
+async fn teardown(sessions: Vec<InferSession>) {
+    for result in join_all(sessions.into_iter().map(InferSession::shutdown)).await {
+        if let Err(err) = result {
+            tracing::warn!("{err:#}");
+        }
+    }
+}
+
+/// Infers the interfaces of `files`, whose content is in `contents`, into `dests` with all the
+/// `sessions`, each one taking the next file once it's done with one. The results are handed to
+/// `finish` in the order of `files`, as soon as the files before them are done, until it breaks.
+/// Returns how many files were finished.
+async fn infer_all(
+    sessions: &mut [InferSession],
+    files: &[PathBuf],
+    contents: &[PathBuf],
+    dests: &[PathBuf],
+    progress: &Progress,
+    finish: impl FnMut(&Path, color_eyre::Result<Inference>) -> ControlFlow<()>,
+) -> usize {
+    let next = Cell::new(0);
+    let results = RefCell::new((0..files.len()).map(|_| None).collect::<Vec<_>>());
+    let finished = Cell::new(0);
+    let stopped = Cell::new(false);
+    let finish = RefCell::new(finish);
+
+    let workers = sessions.iter_mut().map(|session| {
+        let (next, results, finished, stopped, finish) =
+            (&next, &results, &finished, &stopped, &finish);
+        async move {
+            while !stopped.get() && next.get() < files.len() {
+                let index = next.get();
+                next.set(index + 1);
+                let file = files[index].display();
+                progress.show(&format!("[{}/{}] inferring {file}", index + 1, files.len()));
+                let result = session
+                    .infer_from(&files[index], &contents[index], &dests[index])
+                    .await;
+
+                let mut results = results.borrow_mut();
+                results[index] = Some(result);
+                while let Some(result) = results.get_mut(finished.get()).and_then(Option::take) {
+                    if stopped.get() {
+                        break;
+                    }
+                    progress.clear();
+                    let flow = (finish.borrow_mut())(&files[finished.get()], result);
+                    finished.set(finished.get() + 1);
+                    stopped.set(flow.is_break());
+                }
+            }
+        }
+    });
+    join_all(workers).await;
+    finished.get()
+}
+
+/// Outputs the interface inferred for `file` as asked.
+fn finish(
+    args: &Args,
+    root_dir: &Path,
+    file: &Path,
+    result: color_eyre::Result<Inference>,
+) -> Result<Record, (Failure, Report)> {
+    let mli_file = args.mli_path(root_dir, file);
+    let inference = result.map_err(|err| (Failure::of(&err), err))?;
+    print_diagnostics(file, &inference);
+    if args.fail_on_error && inference.has_errors() {
+        return Err((Failure::TypeErrors, eyre!("{} has errors", file.display())));
+    }
+    if args.fail_if_empty && inference.interface.trim().is_empty() {
+        let err = eyre!(
+            "the interface of {} is empty, the server may not be done indexing the project, \
+             --verbose and its diagnostics should tell",
+            file.display()
+        );
+        return Err((Failure::Inference, err));
+    }
+    let interface = match &args.post_process {
+        Some(command) => {
+            post_process(command, &inference.interface).map_err(|err| (Failure::Inference, err))?
+        }
+        None => inference.interface,
+    };
+    let mut header = args.header(file);
+    // the existing interface already has its header
+    if args.append_to_existing && interface.starts_with(&header) {
+        header.clear();
+    }
+    let outcome = output(args, root_dir, &mli_file, &header, &interface)
+        .map_err(|err| (Failure::Inference, err))?;
+    if args.stage && outcome != Outcome::Reported {
+        let shown = args.shown(root_dir, &mli_file);
+        stage(&mli_file, shown).map_err(|err| (Failure::Inference, err))?;
+    }
+    Ok(Record {
+        source: file.to_path_buf(),
+        mli: args.shown(root_dir, &mli_file).to_path_buf(),
+        interface,
+        formatted: inference.formatted,
+        diagnostics: inference.diagnostics,
+        outcome,
+    })
+}
+
+/// Stages `file` with `git add`, in the repository it belongs to. It's printed as `shown`.
+fn stage(file: &Path, shown: &Path) -> color_eyre::Result<()> {
+    let (Some(dir), Some(name)) = (file.parent(), file.file_name()) else {
+        return Err(eyre!("couldn't stage {}", shown.display()));
+    };
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["add", "--"])
+        .arg(name)
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err("couldn't run git")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!(
+            "couldn't stage {}: {}",
+            shown.display(),
+            stderr.trim_end()
+        ));
+    }
     Ok(())
 }
 
-fn apply_edits(text: &mut Rope, edits: &[TextEdit]) {
-    for edit in edits {
-        let start =
-            text.line_to_byte(edit.range.start.line as usize) + edit.range.start.character as usize;
-        let end =
-            text.line_to_byte(edit.range.end.line as usize) + edit.range.end.character as usize;
+/// Sums up the run, with the files sorted so it doesn't depend on which finished first.
+fn summary(
+    records: &[Record],
+    cached: &[PathBuf],
+    mut failed: Vec<PathBuf>,
+    skipped: &[PathBuf],
+    processed: usize,
+    started: Instant,
+) -> Summary {
+    let sources = |outcome| {
+        let mut sources = records
+            .iter()
+            .filter(|record| record.outcome == outcome)
+            .map(|record| record.source.clone())
+            .collect::<Vec<_>>();
+        sources.sort();
+        sources
+    };
+    let mut skipped = skipped.to_vec();
+    failed.sort();
+    skipped.sort();
+    Summary {
+        processed,
+        written: sources(Outcome::Written),
+        unchanged: {
+            let mut unchanged = sources(Outcome::Unchanged);
+            unchanged.extend_from_slice(cached);
+            unchanged.sort();
+            unchanged
+        },
+        failed,
+        skipped,
+        duration: started.elapsed().as_secs_f64(),
+    }
+}
+
+/// Infers the interface of `file` and outputs it as asked.
+async fn process(
+    args: &Args,
+    session: &mut InferSession,
+    root_dir: &Path,
+    file: &Path,
+) -> Result<Record, (Failure, Report)> {
+    let result = session.infer(file, &args.mli_path(root_dir, file)).await;
+    finish(args, root_dir, file, result)
+}
 
-        text.remove(start..end);
-        text.insert(start, &edit.new_text);
+/// Answers the requests read from stdin, one JSON object per line, with one JSON response per
+/// line on stdout, inferring them all with `session`. Stops once stdin is closed.
+async fn serve(args: &Args, session: &mut InferSession, root_dir: &Path) -> color_eyre::Result<()> {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines.next_line().await.wrap_err("couldn't read stdin")? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ServeRequest>(&line) {
+            Ok(request) => {
+                let file = request.file.clone();
+                let result = answer(args, session, root_dir, request).await;
+                result.unwrap_or_else(|err| ServeResponse {
+                    file: Some(file),
+                    error: Some(format!("{err:#}")),
+                    ..ServeResponse::default()
+                })
+            }
+            Err(err) => ServeResponse {
+                error: Some(format!("invalid request: {err}")),
+                ..ServeResponse::default()
+            },
+        };
+        println!("{}", serde_json::to_string(&response).unwrap());
     }
+    Ok(())
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> color_eyre::Result<()> {
-    let args = Args::parse();
-    let root_dir = Path::new(&args.root_dir)
-        .canonicalize()
-        .expect("test root should be valid");
+/// Infers the interface `request` asks for, and writes it if asked to.
+async fn answer(
+    args: &Args,
+    session: &mut InferSession,
+    root_dir: &Path,
+    request: ServeRequest,
+) -> color_eyre::Result<ServeResponse> {
+    let file = PathBuf::from(&request.file);
+    let mli_file = args.mli_path(root_dir, &file);
+    let inference = match &request.options.text {
+        // the session gives the buffer a version, so it never sends one the server already saw
+        Some(text) => session.infer_buffer(&file, text, None, &mli_file).await?,
+        None => session.infer(&file, &mli_file).await?,
+    };
+    // the interface as it's written, so both are the same
+    let interface = format!("{}{}", args.header(&file), inference.interface);
+    let written = if request.options.write {
+        let mut outcome = InferOutcome::new(&mli_file, interface.clone(), args.encoding)?;
+        create_parent(args, &mli_file)?;
+        outcome.write()?;
+        Some(outcome.wrote)
+    } else {
+        None
+    };
+    Ok(ServeResponse {
+        file: Some(request.file),
+        interface: Some(interface),
+        diagnostics: inference.diagnostics,
+        written,
+        error: None,
+    })
+}
 
-    let mut real_file = root_dir.join(&args.file);
-    let text = read_to_string(&real_file).wrap_err("couldn't read file")?;
+/// Infers the interfaces of `files` again whenever they change, until the watcher stops.
+async fn watch(
+    args: &Args,
+    session: &mut InferSession,
+    root_dir: &Path,
+    files: &[PathBuf],
+) -> color_eyre::Result<()> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _: Result<_, _> = tx.send(event);
+    })
+    .wrap_err("couldn't watch files")?;
 
-    let (indexed_tx, _) = oneshot::channel();
+    // watch the directories rather than the files, editors often save by replacing the file
+    let sources = files
+        .iter()
+        .map(|file| root_dir.join(file))
+        .collect::<Vec<_>>();
+    let dirs = sources
+        .iter()
+        .filter_map(|source| source.parent())
+        .collect::<BTreeSet<_>>();
+    for dir in dirs {
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .wrap_err_with(|| format!("couldn't watch {}", dir.display()))?;
+    }
+    eprintln!("watching for changes");
 
-    let (mainloop, mut server) = async_lsp::MainLoop::new_client(|_server| {
-        let mut router = Router::new(ClientState {
-            indexed_tx: Some(indexed_tx),
-        });
-        router
-            .notification::<Progress>(|this, prog| {
-                tracing::debug!("{:?} {:?}", prog.token, prog.value);
-                if matches!(prog.token, NumberOrString::String(_))
-                    && matches!(
-                        prog.value,
-                        ProgressParamsValue::WorkDone(WorkDoneProgress::End(_))
-                    )
-                {
-                    // Sometimes rust-analyzer auto-index multiple times?
-                    if let Some(tx) = this.indexed_tx.take() {
-                        let _: Result<_, _> = tx.send(());
+    while let Some(event) = rx.recv().await {
+        let mut changed = BTreeSet::new();
+        changed_sources(event, &sources, &mut changed);
+        while let Ok(Some(event)) = timeout(DEBOUNCE, rx.recv()).await {
+            changed_sources(event, &sources, &mut changed);
+        }
+
+        for index in changed {
+            let file = &files[index];
+            match process(args, session, root_dir, file).await {
+                Ok(record) => {
+                    if args.output_format == OutputFormat::Json {
+                        println!("{}", serde_json::to_string(&record).unwrap());
                     }
+                    eprintln!("{} changed, inferred its interface again", file.display());
                 }
-                ControlFlow::Continue(())
-            })
-            .notification::<PublishDiagnostics>(|_, _| ControlFlow::Continue(()))
-            .notification::<ShowMessage>(|_, params| {
-                tracing::debug!("show message: {:?}: {}", params.typ, params.message);
-                ControlFlow::Continue(())
-            })
-            .notification::<LogMessage>(|_, params| {
-                tracing::debug!("log message: {:?}: {}", params.typ, params.message);
-                ControlFlow::Continue(())
-            })
-            .event(|_, _: Stop| ControlFlow::Break(Ok(())));
+                Err((_, err)) => eprintln!("{err:#}"),
+            }
+        }
+    }
+
+    Ok(())
+}
 
-        ServiceBuilder::new()
-            .layer(CatchUnwindLayer::default())
-            .layer(ConcurrencyLayer::default())
-            .service(router)
+/// Adds the index of every source `event` is about to `changed`.
+fn changed_sources(
+    event: notify::Result<notify::Event>,
+    sources: &[PathBuf],
+    changed: &mut BTreeSet<usize>,
+) {
+    let event = match event {
+        Ok(event) => event,
+        Err(err) => {
+            tracing::warn!("couldn't watch files: {err}");
+            return;
+        }
+    };
+    if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+        return;
+    }
+    for path in &event.paths {
+        if let Some(index) = sources.iter().position(|source| source == path) {
+            changed.insert(index);
+        }
+    }
+}
+
+/// Resolves on the first Ctrl-C, so the servers can be shut down cleanly, and exits right away on
+/// the second one.
+async fn interrupted() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        // without a handler Ctrl-C just kills the process
+        return std::future::pending().await;
+    }
+    eprintln!("interrupted, shutting down, press Ctrl-C again to exit now");
+    tokio::spawn(async {
+        let _: Result<_, _> = tokio::signal::ctrl_c().await;
+        std::process::exit(Failure::Interrupted as i32);
     });
+}
 
-    tracing_subscriber::fmt()
-        .with_max_level(Level::INFO)
-        .with_ansi(false)
-        .with_writer(std::io::stderr)
-        .init();
+/// The sources under the directory `dir`, which is `path` resolved against `root_dir`, matching
+/// the --include and --exclude globs. They're returned sorted, relative to `root_dir` when `dir`
+/// is.
+fn find_sources(
+    args: &Args,
+    root_dir: &Path,
+    dir: &Path,
+    path: &Path,
+) -> color_eyre::Result<Vec<PathBuf>> {
+    let include = glob_set(&args.include)?;
+    let exclude = glob_set(&args.exclude)?;
 
-    let child = async_process::Command::new("ocamllsp")
-        .current_dir(&root_dir)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .kill_on_drop(true)
-        .spawn()
-        .expect("Failed run rust-analyzer");
-    let stdout = child.stdout.unwrap();
-    let stdin = child.stdin.unwrap();
+    let walker = ignore::WalkBuilder::new(path)
+        .standard_filters(false)
+        .hidden(true)
+        .git_ignore(args.respect_gitignore)
+        .git_exclude(args.respect_gitignore)
+        .require_git(false)
+        // dune copies the sources in there
+        .filter_entry(|entry| entry.file_name() != "_build")
+        .build();
+
+    let mut sources = Vec::new();
+    for entry in walker {
+        let entry = entry.wrap_err_with(|| format!("couldn't read {}", path.display()))?;
+        let source = entry.path();
+        let is_source = matches!(
+            source.extension().and_then(|ext| ext.to_str()),
+            Some("ml" | "re")
+        );
+        if !is_source || !entry.file_type().is_some_and(|kind| kind.is_file()) {
+            continue;
+        }
+
+        let relative = source.strip_prefix(root_dir).ok();
+        let matched = relative.unwrap_or(source);
+        if (!args.include.is_empty() && !include.is_match(matched)) || exclude.is_match(matched) {
+            continue;
+        }
+        sources.push(match relative {
+            Some(relative) if dir.is_relative() => relative.to_path_buf(),
+            _ => dir.join(source.strip_prefix(path).unwrap()),
+        });
+    }
+    sources.sort();
+    Ok(sources)
+}
+
+fn glob_set(globs: &[Glob]) -> color_eyre::Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for glob in globs {
+        set.add(glob.clone());
+    }
+    set.build().wrap_err("invalid glob")
+}
+
+/// What `program` prints with `--version`.
+fn server_version(program: impl AsRef<OsStr>, args: &[String]) -> std::io::Result<Output> {
+    std::process::Command::new(program)
+        .args(args)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+}
+
+/// Where `program` is run from, looked up in the `PATH` unless it's a path.
+fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains(std::path::MAIN_SEPARATOR) {
+        return Path::new(program).is_file().then(|| PathBuf::from(program));
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+/// Checks that the language server can infer interfaces, by inferring one in a scratch
+/// project, printing whether each step passed.
+async fn doctor(program: String, args: Vec<String>) -> Result<(), Failure> {
+    let check = |passed: bool, message: &str| {
+        eprintln!("{} {message}", if passed { "ok  " } else { "FAIL" });
+        passed
+    };
+
+    let Some(path) = find_program(&program) else {
+        check(false, &format!("{program} isn't on the PATH"));
+        return Err(Failure::Spawn);
+    };
+    check(true, &format!("found {}", path.display()));
+
+    match server_version(&path, &args) {
+        Ok(output) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            let version = Some(version.trim())
+                .filter(|version| !version.is_empty())
+                .unwrap_or("unknown");
+            check(true, &format!("{program} runs, version {version}"));
+        }
+        Ok(output) => {
+            check(
+                false,
+                &format!("{program} --version failed ({})", output.status),
+            );
+            return Err(Failure::Spawn);
+        }
+        Err(err) => {
+            check(false, &format!("couldn't run {program}: {err}"));
+            return Err(Failure::Spawn);
+        }
+    }
 
-    let mainloop_fut = tokio::spawn(async move {
-        mainloop.run_buffered(stdout, stdin).await.unwrap();
+    let root_dir = std::env::temp_dir().join(format!("infer-mli-doctor-{}", std::process::id()));
+    let project = std::fs::create_dir_all(&root_dir).and_then(|()| {
+        std::fs::write(root_dir.join("dune-project"), "(lang dune 3.0)\n")?;
+        std::fs::write(root_dir.join("dune"), "(library (name doctor))\n")?;
+        std::fs::write(root_dir.join("doctor.ml"), "let answer = 42\n")
     });
+    let result = match project {
+        Ok(()) => doctor_session(&root_dir, program, args, check).await,
+        Err(err) => {
+            check(
+                false,
+                &format!("couldn't create a project to test with: {err}"),
+            );
+            Err(Failure::Inference)
+        }
+    };
+    let _: Result<_, _> = std::fs::remove_dir_all(&root_dir);
+    result
+}
 
-    // Initialize.
-    server
-        .initialize(InitializeParams {
-            workspace_folders: Some(vec![WorkspaceFolder {
-                uri: Url::from_file_path(&root_dir).unwrap(),
-                name: "root".into(),
-            }]),
-            capabilities: ClientCapabilities {
-                window: Some(WindowClientCapabilities {
-                    work_done_progress: Some(true),
-                    ..WindowClientCapabilities::default()
-                }),
-                ..ClientCapabilities::default()
-            },
-            ..InitializeParams::default()
-        })
-        .await
-        .wrap_err("couldn't initialize")?;
+/// The checks of [`doctor`] that need the server running in the project at `root_dir`.
+async fn doctor_session(
+    root_dir: &Path,
+    program: String,
+    args: Vec<String>,
+    check: impl Fn(bool, &str) -> bool,
+) -> Result<(), Failure> {
+    let server = ServerCommand {
+        program: program.clone(),
+        args,
+        stderr: ServerStderr::Null,
+        settings: None,
+    };
+    let config = InferConfig::builder()
+        .server(server)
+        .timeout(Duration::from_secs(30))
+        .index_timeout(Duration::from_secs(10))
+        .merge_docs(false)
+        .build();
+    let mut session = match InferSession::start(root_dir, &config).await {
+        Ok(session) => session,
+        Err(err) => {
+            check(false, &format!("couldn't start {program}: {err:#}"));
+            return Err(Failure::of(&err));
+        }
+    };
+    check(true, &format!("{program} starts and initializes"));
 
-    server
-        .initialized(InitializedParams {})
-        .wrap_err("couldn't initialize")?;
+    let mut passed = check(
+        session.capabilities().formatting,
+        "the server can format interfaces",
+    );
+    let file = Path::new("doctor.ml");
+    match session.infer(file, &interface_path(file)).await {
+        // whatever the formatting
+        Ok(inference)
+            if inference
+                .interface
+                .split_whitespace()
+                .eq(["val", "answer", ":", "int"]) =>
+        {
+            passed &= check(true, "the server infers interfaces");
+        }
+        Ok(inference) => {
+            let interface = inference.interface.trim();
+            let message = format!("the server inferred an unexpected interface: {interface:?}");
+            passed &= check(false, &message);
+        }
+        Err(err) => passed &= check(false, &format!("{err:#}")),
+    }
 
-    open_file(&mut server, real_file.clone(), &text).await?;
+    teardown(vec![session]).await;
+    if passed {
+        Ok(())
+    } else {
+        Err(Failure::Inference)
+    }
+}
 
-    let Ok(text) = infer_intf(&mut server, &mut real_file).await else {
-        // Shutdown.
-        server.shutdown(()).await.wrap_err("couldn't shutdown")?;
-        server.exit(()).wrap_err("couldn't exit")?;
+/// A line on stderr telling what's going on, redrawn in place. It's only shown on a terminal, so
+/// it doesn't end up in logs.
+struct Progress {
+    enabled: bool,
+    shown: Cell<bool>,
+}
+
+impl Progress {
+    fn new(args: &Args) -> Self {
+        Self {
+            enabled: !args.quiet && std::io::stderr().is_terminal(),
+            shown: Cell::new(false),
+        }
+    }
+
+    fn show(&self, message: &str) {
+        if self.enabled {
+            eprint!("\r\x1b[K{message}");
+            self.shown.set(true);
+        }
+    }
+
+    /// Clears the line, before printing anything else.
+    fn clear(&self) {
+        if self.shown.replace(false) {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+/// A file removed when dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _: Result<_, _> = std::fs::remove_file(&self.0);
+    }
+}
+
+/// The first flag of `args` that conflicts with --stdin, as `--file -` reads stdin too.
+fn stdin_conflict(args: &Args) -> Option<&'static str> {
+    [
+        ("--check", args.check),
+        ("--diff", args.diff),
+        ("--watch", args.watch),
+        ("--output", args.output.is_some()),
+        ("--out-dir", args.out_dir.is_some()),
+        ("--stage", args.stage),
+        ("--cache", args.cache.is_some()),
+    ]
+    .into_iter()
+    .find_map(|(flag, set)| set.then_some(flag))
+}
+
+/// The first two flags of `args` reading stdin, which can only be read once. Clap knows about
+/// some of them, but not about the `-` values.
+fn stdin_readers(args: &Args) -> Option<(&'static str, &'static str)> {
+    let readers = [
+        ("--stdin", args.stdin),
+        ("--file -", args.file == ["-"]),
+        ("--serve", args.serve),
+        ("--request -", args.request.as_deref() == Some("-")),
+        ("--files-from -", args.files_from.as_deref() == Some("-")),
+        ("--only -", args.only.as_deref() == Some("-")),
+    ]
+    .into_iter()
+    .filter_map(|(flag, set)| set.then_some(flag))
+    .collect::<Vec<_>>();
+    match readers[..] {
+        [first, second, ..] => Some((first, second)),
+        _ => None,
+    }
+}
+
+/// Reads the buffer request in `path`, `-` being stdin.
+fn read_request(path: &str) -> color_eyre::Result<BufferRequest> {
+    let request = if path == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path)?
+    };
+    Ok(serde_json::from_str(&request)?)
+}
+
+/// Reads the non-blank lines of `path`, like paths or names listed one per line, `-` being stdin.
+fn read_lines(path: &str) -> std::io::Result<Vec<String>> {
+    let list = if path == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path)?
+    };
+    Ok(list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args = Args::parse();
+    if let Some(Command::Completions { shell }) = args.command {
+        let mut command = Args::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return;
+    }
+
+    let result = match args.command {
+        Some(Command::Doctor { server }) => doctor(server.ocamllsp_path, server.ocamllsp_arg).await,
+        _ => run(args).await,
+    };
+    if let Err(failure) = result {
+        std::process::exit(failure as i32);
+    }
+}
 
-        server.emit(Stop).wrap_err("couldn't emit stop event")?;
-        mainloop_fut.await.wrap_err("couldn't finish main loop")?;
+async fn run(mut args: Args) -> Result<(), Failure> {
+    let run_started = Instant::now();
+    if let Some((first, second)) = stdin_readers(&args) {
+        eprintln!("{first} and {second} both read stdin, only one of them can");
+        return Err(Failure::Usage);
+    }
+    if args.file == ["-"] {
+        // clap only knows --stdin conflicts with them
+        if let Some(flag) = stdin_conflict(&args) {
+            eprintln!("--file - reads the source from stdin, it can't be used with {flag}");
+            return Err(Failure::Usage);
+        }
+        args.file.clear();
+        args.stdin = true;
+    }
+    let request = match &args.request {
+        Some(path) => match read_request(path) {
+            Ok(request) => Some(request),
+            Err(err) => {
+                eprintln!("couldn't read {path}: {err}");
+                return Err(Failure::Inference);
+            }
+        },
+        None => None,
+    };
+    if let Some(request) = &request {
+        args.stdout = true;
+        args.file = vec![request.path.clone()];
+    }
+    let stdin = if args.stdin {
+        args.stdout = true;
+        let mut source = Vec::new();
+        match std::io::stdin().read_to_end(&mut source) {
+            Ok(_) => Some(source),
+            Err(err) => {
+                eprintln!("couldn't read stdin: {err}");
+                return Err(Failure::Inference);
+            }
+        }
+    } else {
+        None
+    };
 
+    let mut inputs = args.file.clone();
+    if let Some(files_from) = &args.files_from {
+        match read_lines(files_from) {
+            Ok(files) => inputs.extend(files),
+            Err(err) => {
+                eprintln!("couldn't read {files_from}: {err}");
+                return Err(Failure::Inference);
+            }
+        }
+    }
+    if inputs.is_empty() && stdin.is_none() && !args.serve {
         return Ok(());
+    }
+
+    let (root_dir, files) = match &args.root_dir {
+        Some(root_dir) => {
+            let root_dir = match Path::new(root_dir).canonicalize() {
+                Ok(root_dir) => root_dir,
+                Err(err) => {
+                    eprintln!("couldn't resolve {root_dir}: {err}");
+                    return Err(Failure::Usage);
+                }
+            };
+            (root_dir, inputs.iter().map(PathBuf::from).collect())
+        }
+        None => {
+            // stdin belongs to the project of the current directory
+            let first = inputs.first().map_or("-", String::as_str);
+            let files = inputs
+                .iter()
+                .map(std::path::absolute)
+                .collect::<Result<Vec<_>, _>>();
+            match files.and_then(|files| Ok((std::path::absolute(first)?, files))) {
+                Ok((first, files)) => (find_project_root(&first), files),
+                Err(err) => {
+                    eprintln!("couldn't resolve file: {err}");
+                    return Err(Failure::Inference);
+                }
+            }
+        }
     };
 
-    std::fs::write(&real_file, text).wrap_err("couldn't write file")?;
-    println!("{}", real_file.to_string_lossy());
+    let mut sources = Vec::with_capacity(files.len());
+    for file in files {
+        let path = root_dir.join(&file);
+        if !path.is_dir() {
+            sources.push(file);
+            continue;
+        }
+        match find_sources(&args, &root_dir, &file, &path) {
+            Ok(found) => sources.extend(found),
+            Err(err) => {
+                eprintln!("{err:#}");
+                return Err(Failure::Inference);
+            }
+        }
+    }
+    let mut files = sources;
+    // whether the JSON output is an array, from what was asked for rather than what the cache
+    // leaves, so a batch is always printed as one
+    let batch = files.len() > 1;
+    if files.is_empty() && stdin.is_none() && !args.serve {
+        eprintln!("no sources found");
+        return Ok(());
+    }
 
-    // Shutdown.
-    server.shutdown(()).await.wrap_err("couldn't shutdown")?;
-    server.exit(()).wrap_err("couldn't exit")?;
+    // where the content of each file is read from
+    let mut contents = files.clone();
+    // the server is told the source is in the project, so it resolves its modules, but it's stored
+    // elsewhere as the project may be read-only
+    let _stdin_file = match stdin {
+        Some(source) => {
+            let name = format!(".infer-mli-stdin-{}.ml", std::process::id());
+            let temp_dir = args.temp_dir.clone().unwrap_or_else(std::env::temp_dir);
+            let file = TempFile(temp_dir.join(&name));
+            if let Err(err) = std::fs::write(&file.0, source) {
+                eprintln!("couldn't write {}: {err}", file.0.display());
+                return Err(Failure::Inference);
+            }
+            files.push(root_dir.join(name));
+            contents.push(file.0.clone());
+            Some(file)
+        }
+        None => None,
+    };
 
-    server.emit(Stop).wrap_err("couldn't emit stop event")?;
-    mainloop_fut.await.wrap_err("couldn't finish main loop")?;
-    Ok(())
+    if args.compare_with.is_some() && files.len() > 1 {
+        eprintln!("--compare-with can only compare the interface of a single file");
+        return Err(Failure::Usage);
+    }
+    if args.relative_paths {
+        // the session resolves them against the root, so they're printed as is everywhere
+        for path in files.iter_mut().chain(&mut contents) {
+            if let Ok(relative) = path.strip_prefix(&root_dir) {
+                *path = relative.to_path_buf();
+            }
+        }
+    }
+    if let Some(output) = &args.output {
+        if files.len() > 1 && !Path::new(output).is_dir() {
+            eprintln!("--output must be an existing directory when inferring several files");
+            return Err(Failure::Usage);
+        }
+    }
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(args.log_level())
+        .with_writer(std::io::stderr);
+    if args.log_json {
+        subscriber.json().init();
+    } else {
+        subscriber.with_ansi(false).init();
+    }
+
+    let mut config = args.config();
+    if !args.print_only.is_empty() {
+        args.stdout = true;
+        config.only = Some(args.print_only.iter().cloned().collect());
+    }
+    if let Some(only) = &args.only {
+        match read_lines(only) {
+            Ok(names) => config.only = Some(names.into_iter().collect()),
+            Err(err) => {
+                eprintln!("couldn't read {only}: {err}");
+                return Err(Failure::Inference);
+            }
+        }
+    }
+    let mut cache = None;
+    let mut cached = Vec::new();
+    if let Some(path) = &args.cache {
+        match cache_key(&args, &config, &root_dir, &files) {
+            Some(key) => {
+                let loaded = Cache::load(path, key);
+                let (fresh, stale) = files.into_iter().partition::<Vec<_>, _>(|file| {
+                    let mli_file = args.mli_path(&root_dir, file);
+                    loaded.is_fresh(&root_dir.join(file), &mli_file)
+                });
+                for file in &fresh {
+                    if args.output_format == OutputFormat::Text && !args.check {
+                        let mli_file = args.mli_path(&root_dir, file);
+                        print_unchanged(args.shown(&root_dir, &mli_file));
+                    }
+                }
+                cached = fresh;
+                files = stale;
+                contents = files.clone();
+                cache = Some(loaded);
+            }
+            None => tracing::warn!("couldn't get the version of the server, not using the cache"),
+        }
+    }
+
+    let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let jobs = if args.serve {
+        1
+    } else {
+        args.jobs.clamp(1, cpus).min(files.len())
+    };
+    let mut sessions = Vec::with_capacity(jobs);
+    let mut start_failure = None;
+    let progress = Progress::new(&args);
+    progress.show(&format!(
+        "starting {} and indexing the project",
+        args.server.ocamllsp_path
+    ));
+    let started = tokio::select! {
+        results = join_all((0..jobs).map(|_| start_session(&root_dir, &config))) => results,
+        // the servers being started are killed when dropped
+        () = interrupted() => {
+            progress.clear();
+            return Err(Failure::Interrupted);
+        }
+    };
+    progress.clear();
+    for result in started {
+        match result {
+            Ok(session) => sessions.push(session),
+            Err((failure, err)) => {
+                eprintln!("{err:#}");
+                start_failure.get_or_insert(failure);
+            }
+        }
+    }
+    if let Some(failure) = start_failure {
+        teardown(sessions).await;
+        return Err(failure);
+    }
+
+    if let (true, Some(session)) = (args.print_server_info, sessions.first()) {
+        print_server_info(session);
+    }
+    if args.time {
+        for session in &sessions {
+            let timings = session.startup_timings();
+            eprintln!(
+                "server started: spawn {}, initialize {}, index {}",
+                human_duration(timings.spawn),
+                human_duration(timings.initialize),
+                human_duration(timings.index)
+            );
+        }
+    }
+    if args.serve {
+        let served = tokio::select! {
+            result = serve(&args, &mut sessions[0], &root_dir) => result,
+            () = interrupted() => {
+                teardown(sessions).await;
+                return Err(Failure::Interrupted);
+            }
+        };
+        teardown(sessions).await;
+        return served.map_err(|err| {
+            eprintln!("{err:#}");
+            Failure::Inference
+        });
+    }
+
+    let mut failed = 0;
+    let mut finished = 0;
+    let mut first_failure = None;
+    let mut failures = Vec::new();
+    let mut records = Vec::new();
+    let mut total = Timings::default();
+    let dests = files
+        .iter()
+        .map(|file| args.mli_path(&root_dir, file))
+        .collect::<Vec<_>>();
+    // a failure shouldn't stop watching the other files
+    let keep_going = args.keep_going || args.watch;
+    let work = async {
+        let mut finish_file = |file: &Path, result: color_eyre::Result<Inference>| {
+            if let (true, Ok(inference)) = (args.time, &result) {
+                let timings = inference.timings;
+                eprintln!(
+                    "{}: infer {}, format {}",
+                    file.display(),
+                    human_duration(timings.infer),
+                    human_duration(timings.format)
+                );
+                total.infer += timings.infer;
+                total.format += timings.format;
+            }
+            match finish(&args, &root_dir, file, result) {
+                Ok(record) => records.push(record),
+                Err((failure, err)) => {
+                    eprintln!("{err:#}");
+                    first_failure.get_or_insert(failure);
+                    failures.push(file.to_path_buf());
+                    failed += 1;
+                    if !keep_going {
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+            ControlFlow::Continue(())
+        };
+        finished = match &request {
+            Some(request) => {
+                let (file, text, version) = (&files[0], &request.text, request.version);
+                let result = sessions[0]
+                    .infer_buffer(file, text, Some(version), &dests[0])
+                    .await;
+                let _ = finish_file(file, result);
+                1
+            }
+            None => {
+                infer_all(
+                    &mut sessions,
+                    &files,
+                    &contents,
+                    &dests,
+                    &progress,
+                    finish_file,
+                )
+                .await
+            }
+        };
+
+        // a single file that failed has nothing to print
+        if args.output_format == OutputFormat::Json && (batch || !records.is_empty()) {
+            let json = if batch {
+                serde_json::to_string_pretty(&records)
+            } else {
+                serde_json::to_string_pretty(&records[0])
+            };
+            println!("{}", json.unwrap());
+        }
+
+        if args.watch {
+            if let Err(err) = watch(&args, &mut sessions[0], &root_dir, &files).await {
+                eprintln!("{err:#}");
+                first_failure.get_or_insert(Failure::Inference);
+            }
+        }
+    };
+    let interrupted = tokio::select! {
+        () = work => false,
+        () = interrupted() => true,
+    };
+
+    teardown(sessions).await;
+    if let (Some(path), Some(mut cache)) = (&args.cache, cache) {
+        // a passing check means the interface is up to date even though nothing was written
+        let up_to_date = records
+            .iter()
+            .filter(|record| record.outcome != Outcome::Reported || args.check);
+        for record in up_to_date {
+            let source = root_dir.join(&record.source);
+            if let Some(entry) = Cache::entry(&source, &root_dir.join(&record.mli)) {
+                cache.files.insert(source, entry);
+            }
+        }
+        if let Err(err) = cache.save(path) {
+            eprintln!("couldn't write {}: {err}", path.display());
+        }
+    }
+    if interrupted {
+        return Err(Failure::Interrupted);
+    }
+    if args.time {
+        eprintln!(
+            "total: infer {}, format {}, {} overall",
+            human_duration(total.infer),
+            human_duration(total.format),
+            human_duration(run_started.elapsed())
+        );
+    }
+    if let Some(path) = &args.summary_json {
+        let summary = summary(
+            &records,
+            &cached,
+            failures,
+            &files[finished..],
+            finished,
+            run_started,
+        );
+        let json = serde_json::to_string_pretty(&summary).unwrap();
+        if let Err(err) = std::fs::write(path, format!("{json}\n")) {
+            eprintln!("couldn't write {}: {err}", path.display());
+            first_failure.get_or_insert(Failure::Inference);
+        }
+    }
+
+    // --keep-going tells how the batch went either way, as the failures may have scrolled away
+    if files.len() > 1 && (first_failure.is_some() || args.keep_going) {
+        let skipped = files.len() - finished;
+        eprint!(
+            "{} of {} files succeeded, {failed} failed",
+            finished - failed,
+            files.len()
+        );
+        if skipped > 0 {
+            eprint!(", {skipped} skipped");
+        }
+        eprintln!();
+    }
+    match first_failure {
+        Some(failure) => Err(failure),
+        None => Ok(()),
+    }
 }
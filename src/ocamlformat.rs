@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use lsp_types::FormattingProperty;
+
+/// The `.ocamlformat` nearest to `dir`, looking up to `root_dir`, whose options the interfaces
+/// of the sources in `dir` are formatted with.
+pub fn nearest_ocamlformat(dir: &Path, root_dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .take_while(|dir| dir.starts_with(root_dir))
+        .map(|dir| dir.join(".ocamlformat"))
+        .find(|path| path.is_file())
+}
+
+/// The options of the `.ocamlformat` nearest to `dir`, looking up to `root_dir`, like `margin`
+/// or `profile`. There are none when there's no `.ocamlformat`.
+pub(crate) fn ocamlformat_options(
+    dir: &Path,
+    root_dir: &Path,
+) -> HashMap<String, FormattingProperty> {
+    let Some(config) = nearest_ocamlformat(dir, root_dir) else {
+        return HashMap::new();
+    };
+    let text = match std::fs::read_to_string(&config) {
+        Ok(text) => text,
+        Err(err) => {
+            tracing::warn!("couldn't read {}: {err}", config.display());
+            return HashMap::new();
+        }
+    };
+    tracing::debug!("formatting with the options of {}", config.display());
+    parse_options(&text)
+}
+
+/// The `option = value` lines of a `.ocamlformat`, skipping comments.
+fn parse_options(text: &str) -> HashMap<String, FormattingProperty> {
+    text.lines()
+        .map(|line| line.split_once('#').map_or(line, |(line, _)| line))
+        .filter_map(|line| line.split_once('='))
+        .map(|(option, value)| {
+            let value = value.trim();
+            let value = match value {
+                "true" => FormattingProperty::Bool(true),
+                "false" => FormattingProperty::Bool(false),
+                _ => value.parse().map_or_else(
+                    |_| FormattingProperty::String(value.to_string()),
+                    FormattingProperty::Number,
+                ),
+            };
+            (option.trim().to_string(), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_options_skipping_comments_and_blank_lines() {
+        let text = "# the profile\nprofile = default\n\nmargin=80 # columns\n  # indented\nwrap-comments= true\nparse-docstrings =false\n";
+        let options = parse_options(text);
+        assert_eq!(
+            options,
+            HashMap::from([
+                (
+                    "profile".to_string(),
+                    FormattingProperty::String("default".to_string())
+                ),
+                ("margin".to_string(), FormattingProperty::Number(80)),
+                ("wrap-comments".to_string(), FormattingProperty::Bool(true)),
+                (
+                    "parse-docstrings".to_string(),
+                    FormattingProperty::Bool(false)
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_lines_without_options_as_nothing() {
+        assert!(parse_options("version\n# margin = 80\n\n").is_empty());
+    }
+
+    #[test]
+    fn nearest_ocamlformat_stops_at_the_root() {
+        let outer =
+            std::env::temp_dir().join(format!("infer-mli-ocamlformat-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&outer);
+        let root_dir = outer.join("project");
+        let dir = root_dir.join("sub");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // above the root, so not the project's
+        std::fs::write(outer.join(".ocamlformat"), "margin = 60\n").unwrap();
+        assert_eq!(nearest_ocamlformat(&dir, &root_dir), None);
+
+        std::fs::write(root_dir.join(".ocamlformat"), "margin = 70\n").unwrap();
+        assert_eq!(
+            nearest_ocamlformat(&dir, &root_dir),
+            Some(root_dir.join(".ocamlformat"))
+        );
+
+        std::fs::write(dir.join(".ocamlformat"), "margin = 80\n").unwrap();
+        assert_eq!(
+            nearest_ocamlformat(&dir, &root_dir),
+            Some(dir.join(".ocamlformat"))
+        );
+        std::fs::remove_dir_all(outer).unwrap();
+    }
+}
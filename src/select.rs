@@ -0,0 +1,472 @@
+use std::collections::HashSet;
+use std::mem::take;
+
+use crate::docs::{comment_depth, declaration};
+
+/// The kinds of declarations kept along with the declarations referring to them.
+const REFERENCED_KINDS: &[&str] = &["type", "module", "module type", "class", "class type"];
+
+/// The kinds of the top level items that apply to what comes after them rather than declare
+/// names, `open`s, `include`s and floating attributes.
+const SCOPED_KINDS: &[&str] = &["open", "include", "attribute"];
+
+/// A top level declaration, with the comments and blank lines before it.
+struct Item {
+    kind: &'static str,
+    /// Several for recursive types, declared with `and`.
+    names: Vec<String>,
+    /// Where the text declaring each of the names starts, with the comments before it, and where
+    /// its keyword starts.
+    members: Vec<(usize, usize)>,
+    text: String,
+}
+
+impl Item {
+    /// The text declaring the `i`th name on its own, its `and` turned back into the keyword of
+    /// the declaration.
+    fn member(&self, i: usize) -> String {
+        let (start, keyword) = self.members[i];
+        let end = self
+            .members
+            .get(i + 1)
+            .map_or(self.text.len(), |&(end, _)| end);
+        match self.text[keyword..end].strip_prefix("and") {
+            Some(rest) if i > 0 => format!("{}{}{rest}", &self.text[start..keyword], self.kind),
+            _ => self.text[start..end].to_string(),
+        }
+    }
+}
+
+/// Only keeps the top level declarations of `mli` named one of `names`, in the same order, and
+/// the types and modules they refer to. References are found by name, so a few more
+/// declarations than needed may be kept.
+pub(crate) fn select_declarations(mli: &str, names: &HashSet<String>) -> String {
+    let (header, items) = items(mli);
+    let mut kept = items
+        .iter()
+        .map(|item| item.names.iter().any(|name| names.contains(name)))
+        .collect::<Vec<_>>();
+    loop {
+        let referenced = items
+            .iter()
+            .zip(&kept)
+            .filter(|(_, &kept)| kept)
+            .flat_map(|(item, _)| identifiers(&item.text))
+            .collect::<HashSet<_>>();
+        let mut changed = false;
+        for (item, kept) in items.iter().zip(&mut kept) {
+            if !*kept
+                && REFERENCED_KINDS.contains(&item.kind)
+                && item
+                    .names
+                    .iter()
+                    .any(|name| referenced.contains(name.as_str()))
+            {
+                *kept = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    // the opens, includes and attributes apply to what comes after them, which may need them
+    for i in 0..items.len() {
+        if SCOPED_KINDS.contains(&items[i].kind) {
+            kept[i] = kept[i + 1..].contains(&true);
+        }
+    }
+
+    let mut selected = header;
+    for (item, kept) in items.iter().zip(kept) {
+        if kept {
+            selected.push_str(&item.text);
+        }
+    }
+    selected.trim_start_matches(['\r', '\n']).to_string()
+}
+
+/// Appends the top level declarations of `inferred` that `existing` lacks to it, in the same
+/// order, leaving what's already there untouched. A declaration is only missing when its name
+/// isn't declared with the same kind in `existing`, so a type that changed is left as it was
+/// rather than declared twice, and the members of a recursive type `existing` lacks are appended
+/// on their own.
+pub(crate) fn append_missing(existing: &str, inferred: &str) -> String {
+    let declared = items(existing)
+        .1
+        .into_iter()
+        .flat_map(|item| {
+            item.names
+                .into_iter()
+                .map(move |name| (namespace(item.kind), name))
+        })
+        .collect::<HashSet<_>>();
+
+    let items = items(inferred).1;
+    let mut missing = items
+        .iter()
+        .map(|item| {
+            let kind = namespace(item.kind);
+            item.names
+                .iter()
+                .map(|name| !declared.contains(&(kind, name.clone())))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    // an open is only needed again by the declarations appended after it
+    for i in 0..items.len() {
+        if SCOPED_KINDS.contains(&items[i].kind) {
+            let needed = missing[i + 1..]
+                .iter()
+                .zip(&items[i + 1..])
+                .any(|(missing, item)| {
+                    missing.contains(&true) && !SCOPED_KINDS.contains(&item.kind)
+                });
+            missing[i] = vec![needed];
+        }
+    }
+
+    let mut appended = existing.to_string();
+    for (item, missing) in items.iter().zip(missing) {
+        let text = if !missing.contains(&false) {
+            item.text.clone()
+        } else {
+            (0..missing.len())
+                .filter(|&i| missing[i])
+                .map(|i| item.member(i))
+                .collect()
+        };
+        if text.is_empty() {
+            continue;
+        }
+        if !appended.is_empty() && !appended.ends_with('\n') {
+            appended.push('\n');
+        }
+        appended.push_str(&text);
+    }
+    appended
+}
+
+/// Removes the top level lines of `mli` starting with one of `keywords`, like `include` and
+/// `open`, along with the rest of what they started and the comments right before them. The
+/// ones nested in another declaration are left alone.
+pub(crate) fn strip_lines(mli: &str, keywords: &[&str]) -> String {
+    let mut stripped = String::new();
+    // the comments right before the next top level line, dropped along with an include
+    let mut pending = String::new();
+    let mut depth = 0;
+    let mut skipping = false;
+    // the `sig` and `struct` opened by the include being removed and not closed yet
+    let mut blocks = 0;
+
+    for line in mli.split_inclusive('\n') {
+        if depth > 0 {
+            depth += comment_depth(line);
+            if !skipping {
+                pending.push_str(line);
+            }
+            continue;
+        }
+
+        let top_level = !line.starts_with(char::is_whitespace) && !line.trim().is_empty();
+        if skipping {
+            // the rest of the include, and the blank lines after it
+            if blocks > 0 || !top_level {
+                blocks += block_balance(line);
+                continue;
+            }
+            skipping = false;
+        }
+
+        if top_level && line.starts_with("(*") {
+            pending.push_str(line);
+            depth = comment_depth(line);
+        } else if top_level && keywords.contains(&keyword(line)) {
+            pending.clear();
+            skipping = true;
+            blocks = block_balance(line);
+            depth = comment_depth(line);
+        } else {
+            stripped.push_str(&take(&mut pending));
+            stripped.push_str(line);
+            depth = comment_depth(line);
+        }
+    }
+    stripped.push_str(&pending);
+    stripped
+}
+
+/// The keyword starting `line`, like `open!`.
+fn keyword(line: &str) -> &str {
+    line.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '!'))
+        .next()
+        .unwrap_or_default()
+}
+
+/// How many more blocks `line` opens than it closes.
+fn block_balance(line: &str) -> i32 {
+    identifiers(line)
+        .map(|word| match word {
+            "sig" | "struct" | "object" | "begin" => 1,
+            "end" => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// The kind of names `kind` declares, so an `external` is the same value as a `val`.
+fn namespace(kind: &'static str) -> &'static str {
+    match kind {
+        "external" => "val",
+        kind => kind,
+    }
+}
+
+/// The top level declarations of `mli`, along with what comes before the first one.
+fn items(mli: &str) -> (String, Vec<Item>) {
+    // what comes before the first declaration, left as is
+    let mut header = String::new();
+    let mut items: Vec<Item> = Vec::new();
+    let mut pending = String::new();
+    let mut pending_comment = false;
+    let mut depth = 0;
+
+    for line in mli.split_inclusive('\n') {
+        if depth > 0 {
+            depth += comment_depth(line);
+            if pending_comment {
+                pending.push_str(line);
+            } else {
+                push_line(&mut items, &mut header, line);
+            }
+            continue;
+        }
+
+        let top_level = !line.starts_with(char::is_whitespace);
+        if line.trim().is_empty() || top_level && line.starts_with("(*") {
+            // attached to the next declaration
+            pending.push_str(line);
+            depth = comment_depth(line);
+            pending_comment = depth > 0;
+        } else if let Some(kind) = scoped(line).filter(|_| top_level) {
+            // named after the whole line, so the same open is found in another interface
+            items.push(Item {
+                kind,
+                names: vec![line.trim().to_string()],
+                members: vec![(0, pending.len())],
+                text: take(&mut pending) + line,
+            });
+            depth = comment_depth(line);
+            pending_comment = false;
+        } else if let Some((kind, name)) = declaration(line).filter(|_| top_level) {
+            items.push(Item {
+                kind,
+                names: vec![name.to_string()],
+                members: vec![(0, pending.len())],
+                text: take(&mut pending) + line,
+            });
+        } else {
+            if let (Some(name), Some(item)) = (recursive_type(line), items.last_mut()) {
+                item.names.push(name);
+                let start = item.text.len();
+                item.members.push((start, start + pending.len()));
+            }
+            pending.push_str(line);
+            push_line(&mut items, &mut header, &take(&mut pending));
+            depth = comment_depth(line);
+            pending_comment = false;
+        }
+    }
+    push_line(&mut items, &mut header, &pending);
+    (header, items)
+}
+
+/// The kind of `line` when it starts an `open`, an `include` or a floating attribute.
+fn scoped(line: &str) -> Option<&'static str> {
+    if line.starts_with("[@@@") {
+        return Some("attribute");
+    }
+    match keyword(line) {
+        "open" | "open!" => Some("open"),
+        "include" => Some("include"),
+        _ => None,
+    }
+}
+
+/// Adds `line` to the last declaration, or to the header when there's none yet.
+fn push_line(items: &mut [Item], header: &mut String, line: &str) {
+    match items.last_mut() {
+        Some(item) => item.text.push_str(line),
+        None => header.push_str(line),
+    }
+}
+
+/// The name declared by `line` when it continues a recursive type or module declaration.
+fn recursive_type(line: &str) -> Option<String> {
+    let rest = line.strip_prefix("and")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    declaration(&format!("type{rest}")).map(|(_, name)| name.to_string())
+}
+
+/// The words of `text` that may be names.
+fn identifiers(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\''))
+        .filter(|word| !word.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selected(mli: &str, names: &[&str]) -> String {
+        let names = names.iter().map(|name| name.to_string()).collect();
+        select_declarations(mli, &names)
+    }
+
+    #[test]
+    fn keeps_the_types_and_modules_referred_to_transitively() {
+        let mli = "type a = int\ntype b = a list\ntype unused = int\nmodule M : sig\n  type t = b\nend\nval f : M.t -> unit\nval g : unit\n";
+        assert_eq!(
+            selected(mli, &["f"]),
+            "type a = int\ntype b = a list\nmodule M : sig\n  type t = b\nend\nval f : M.t -> unit\n"
+        );
+    }
+
+    #[test]
+    fn keeps_the_order_of_the_interface() {
+        let mli = "val c : int\nval a : int\nval b : int\n";
+        assert_eq!(selected(mli, &["b", "c"]), "val c : int\nval b : int\n");
+    }
+
+    #[test]
+    fn keeps_the_comments_before_the_declarations() {
+        let mli =
+            "(** Doc of a. *)\nval a : int\n\n(** Doc of b,\n    on two lines. *)\nval b : int\n";
+        assert_eq!(
+            selected(mli, &["b"]),
+            "(** Doc of b,\n    on two lines. *)\nval b : int\n"
+        );
+    }
+
+    #[test]
+    fn selects_recursive_modules_by_any_of_their_names() {
+        let mli =
+            "module rec A : sig\n  type t\nend\nand B : sig\n  type t = A.t\nend\nval x : int\n";
+        let modules = "module rec A : sig\n  type t\nend\nand B : sig\n  type t = A.t\nend\n";
+        assert_eq!(selected(mli, &["A"]), modules);
+        assert_eq!(selected(mli, &["B"]), modules);
+    }
+
+    #[test]
+    fn selects_recursive_types_by_any_of_their_names() {
+        let mli = "type t = A of u\nand u = B of t\nval x : int\nval y : u\n";
+        assert_eq!(
+            selected(mli, &["y"]),
+            "type t = A of u\nand u = B of t\nval y : u\n"
+        );
+    }
+
+    #[test]
+    fn opens_are_not_kept_with_the_declaration_before_them() {
+        let mli = "val e : int\nopen! Other\ntype u = Other.t\nval last : u\n";
+        assert_eq!(selected(mli, &["e"]), "val e : int\n");
+    }
+
+    #[test]
+    fn opens_are_kept_with_the_declarations_after_them() {
+        let mli = "val e : int\nopen! Other\ntype u = Other.t\nval last : u\n";
+        assert_eq!(
+            selected(mli, &["last"]),
+            "open! Other\ntype u = Other.t\nval last : u\n"
+        );
+    }
+
+    #[test]
+    fn includes_and_floating_attributes_are_their_own_items() {
+        let mli = "val a : int\n[@@@warning \"-32\"]\ninclude S\nval b : int\n";
+        assert_eq!(selected(mli, &["a"]), "val a : int\n");
+        assert_eq!(
+            selected(mli, &["b"]),
+            "[@@@warning \"-32\"]\ninclude S\nval b : int\n"
+        );
+    }
+
+    #[test]
+    fn strip_lines_leaves_nested_includes_alone() {
+        let mli = "module M : sig\n  include S\n  open T\nend\ninclude S\nval x : int\n";
+        assert_eq!(
+            strip_lines(mli, &["include", "open", "open!"]),
+            "module M : sig\n  include S\n  open T\nend\nval x : int\n"
+        );
+    }
+
+    #[test]
+    fn strip_lines_removes_includes_spanning_several_lines() {
+        let mli = "include struct\n  type t = int\n\n  let x = 1\nend\n\nval y : int\n";
+        assert_eq!(strip_lines(mli, &["include"]), "val y : int\n");
+    }
+
+    #[test]
+    fn strip_lines_removes_opens() {
+        let mli = "open! Stdlib\nopen Other\ninclude S\nval x : int\n";
+        assert_eq!(
+            strip_lines(mli, &["open", "open!"]),
+            "include S\nval x : int\n"
+        );
+    }
+
+    #[test]
+    fn strip_lines_removes_the_comments_before_the_line() {
+        let mli = "(** Doc of\n    the include. *)\ninclude S\n(** Doc of x. *)\nval x : int\n";
+        assert_eq!(
+            strip_lines(mli, &["include"]),
+            "(** Doc of x. *)\nval x : int\n"
+        );
+    }
+
+    #[test]
+    fn appends_everything_to_an_empty_file() {
+        let inferred = "open! Other\ntype t = A | B\n\n(** Doc. *)\nval a : t\n";
+        assert_eq!(append_missing("", inferred), inferred);
+    }
+
+    #[test]
+    fn appends_new_values_only() {
+        let existing = "(** Kept as is. *)\nval a : string\n";
+        let inferred = "val a : int\nexternal b : int -> int = \"b\"\nval c : int\n";
+        assert_eq!(
+            append_missing(existing, inferred),
+            "(** Kept as is. *)\nval a : string\nexternal b : int -> int = \"b\"\nval c : int\n"
+        );
+        assert_eq!(
+            append_missing("val a : int", "val b : int\n"),
+            "val a : int\nval b : int\n"
+        );
+    }
+
+    #[test]
+    fn appends_the_missing_members_of_recursive_types() {
+        let existing = "type t = A\n";
+        let inferred =
+            "type t = A of u\n(** Doc of u. *)\nand u = B of t\nand v = C\nval last : u\n";
+        assert_eq!(
+            append_missing(existing, inferred),
+            "type t = A\n(** Doc of u. *)\ntype u = B of t\ntype v = C\nval last : u\n"
+        );
+        assert_eq!(
+            append_missing("type u = B\ntype v = C\n", inferred),
+            "type u = B\ntype v = C\ntype t = A of u\nval last : u\n"
+        );
+    }
+
+    #[test]
+    fn appends_opens_with_the_declarations_needing_them() {
+        let inferred = "open! Other\nval a : Other.t\nopen Another\nval b : int\n";
+        assert_eq!(
+            append_missing("val b : int\n", inferred),
+            "val b : int\nopen! Other\nval a : Other.t\n"
+        );
+    }
+}
@@ -0,0 +1,1358 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_lsp::concurrency::ConcurrencyLayer;
+use async_lsp::panic::CatchUnwindLayer;
+use async_lsp::router::Router;
+use async_lsp::{ErrorCode, LanguageServer, ServerSocket};
+use async_process::Child;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Report;
+use futures::channel::oneshot;
+use futures::{AsyncRead, AsyncWrite};
+use lsp_types::notification::{LogMessage, Progress, PublishDiagnostics, ShowMessage};
+use lsp_types::request::{Formatting, Request, WorkDoneProgressCreate};
+use lsp_types::{
+    CancelParams, ClientCapabilities, Diagnostic, DidChangeConfigurationParams,
+    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DidSaveTextDocumentParams, DocumentFormattingParams, GeneralClientCapabilities,
+    InitializeParams, InitializeResult, InitializedParams, NumberOrString, OneOf, ProgressParams,
+    ProgressParamsValue, SaveOptions, ServerCapabilities, ServerInfo,
+    TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
+    TextDocumentSyncCapability, TextDocumentSyncOptions, TextDocumentSyncSaveOptions, Url,
+    VersionedTextDocumentIdentifier, WindowClientCapabilities, WorkDoneProgress, WorkspaceFolder,
+};
+use ropey::Rope;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tower::ServiceBuilder;
+
+use crate::docs::merge_doc_comments;
+use crate::edits::apply_edits;
+use crate::ocamlformat::ocamlformat_options;
+use crate::select::{append_missing, select_declarations, strip_lines};
+use crate::{
+    read_text, InferConfig, Inference, PositionEncoding, ServerExited, ServerStderr,
+    StartupTimings, TimedOut, Timings,
+};
+
+/// How long to wait for the server to start reporting progress after `initialized`, servers
+/// that have nothing to index (or never report progress) are assumed to be ready after it.
+const PROGRESS_START_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for the server to exit after the connection to it broke.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait before inferring an empty interface again.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long to wait for the server to publish the diagnostics of a document after inferring its
+/// interface, it publishes them on its own time. Servers that aren't known to publish
+/// diagnostics, and haven't published any yet, aren't waited for.
+const DIAGNOSTICS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The diagnostics published for a document, with the version of the document they were
+/// published for when the server said.
+struct DocumentDiagnostics {
+    version: Option<i32>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// The latest diagnostics published by the server, by document.
+#[derive(Default)]
+struct Published {
+    documents: Mutex<HashMap<Url, DocumentDiagnostics>>,
+    /// Whether the server published any diagnostics.
+    any: AtomicBool,
+    /// Whether the server said which versions of the documents its diagnostics are for.
+    versioned: AtomicBool,
+    /// Notified every time the server publishes diagnostics.
+    notify: Notify,
+}
+
+impl Published {
+    fn insert(&self, url: Url, document: DocumentDiagnostics) {
+        self.any.store(true, Ordering::Relaxed);
+        if document.version.is_some() {
+            self.versioned.store(true, Ordering::Relaxed);
+        }
+        self.documents.lock().unwrap().insert(url, document);
+        self.notify.notify_waiters();
+    }
+
+    /// Whether diagnostics were published for the document at `url` at `version`. Diagnostics
+    /// for a version the server didn't say only count when it never says, as they may be for
+    /// the previous text.
+    fn published(&self, url: &Url, version: i32) -> bool {
+        let versioned = self.versioned.load(Ordering::Relaxed);
+        let documents = self.documents.lock().unwrap();
+        documents
+            .get(url)
+            .is_some_and(|document| match document.version {
+                Some(published) => published == version,
+                None => !versioned,
+            })
+    }
+}
+
+type Diagnostics = Arc<Published>;
+
+/// The prefix of the progress tokens ocamllsp reports its dune builds with, which index the
+/// project. Progress reported with other tokens isn't waited for.
+const INDEXING_TOKEN_PREFIX: &str = "dune-build";
+
+struct ClientState {
+    indexing_tx: Option<oneshot::Sender<()>>,
+    indexed_tx: Option<oneshot::Sender<()>>,
+    /// The indexing progress tokens that began and haven't ended yet.
+    indexing: HashSet<NumberOrString>,
+    diagnostics: Diagnostics,
+}
+
+fn is_indexing(token: &NumberOrString) -> bool {
+    matches!(token, NumberOrString::String(token) if token.starts_with(INDEXING_TOKEN_PREFIX))
+}
+
+impl ClientState {
+    /// Tells when the server starts indexing, and when it's done, from the progress it reported.
+    fn on_progress(&mut self, prog: ProgressParams) {
+        tracing::debug!("{:?} {:?}", prog.token, prog.value);
+        if !is_indexing(&prog.token) {
+            return;
+        }
+        match prog.value {
+            ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(_)) => {
+                self.indexing.insert(prog.token);
+                if let Some(tx) = self.indexing_tx.take() {
+                    let _: Result<_, _> = tx.send(());
+                }
+            }
+            // several builds may be running, the project is indexed once they all ended
+            ProgressParamsValue::WorkDone(WorkDoneProgress::End(_)) => {
+                if self.indexing.remove(&prog.token) && self.indexing.is_empty() {
+                    if let Some(tx) = self.indexed_tx.take() {
+                        let _: Result<_, _> = tx.send(());
+                    }
+                }
+            }
+            ProgressParamsValue::WorkDone(WorkDoneProgress::Report(_)) => {}
+        }
+    }
+}
+
+/// What the server agreed to on initialize.
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    /// The encoding of the positions the server sends.
+    pub encoding: PositionEncoding,
+    /// Whether the server can format documents.
+    pub formatting: bool,
+    /// Whether the server advertised it can infer interfaces, `None` when it didn't say.
+    pub infer_intf: Option<bool>,
+    /// Whether the server wants the text of the documents saved.
+    pub save_text: bool,
+    /// Whether the server may report its progress, so it's worth waiting for it to index the
+    /// project. `ocamllsp` reports the progress of dune builds without declaring it.
+    pub progress: bool,
+    /// Whether the server is known to publish the diagnostics of every document it's sent, like
+    /// `ocamllsp`, so they're worth waiting for.
+    pub diagnostics: bool,
+}
+
+impl Capabilities {
+    fn new(initialize_result: &InitializeResult) -> Self {
+        let capabilities = &initialize_result.capabilities;
+        Self {
+            encoding: PositionEncoding::negotiated(capabilities.position_encoding.as_ref()),
+            formatting: matches!(
+                capabilities.document_formatting_provider,
+                Some(OneOf::Left(true) | OneOf::Right(_))
+            ),
+            infer_intf: capabilities
+                .experimental
+                .as_ref()
+                .and_then(|experimental| experimental.pointer("/ocamllsp/handleInferIntf"))
+                .and_then(serde_json::Value::as_bool),
+            save_text: matches!(
+                capabilities.text_document_sync,
+                Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                            include_text: Some(true)
+                        })),
+                        ..
+                    }
+                ))
+            ),
+            progress: is_ocamllsp(capabilities)
+                || serde_json::to_value(capabilities).is_ok_and(|value| declares_progress(&value)),
+            diagnostics: is_ocamllsp(capabilities),
+        }
+    }
+}
+
+/// Whether the server is `ocamllsp`, told by its experimental capabilities.
+fn is_ocamllsp(capabilities: &ServerCapabilities) -> bool {
+    capabilities
+        .experimental
+        .as_ref()
+        .is_some_and(|experimental| experimental.get("ocamllsp").is_some())
+}
+
+/// Whether any of the options in `capabilities` declare `workDoneProgress`.
+fn declares_progress(capabilities: &serde_json::Value) -> bool {
+    match capabilities {
+        serde_json::Value::Object(options) => options.iter().any(|(key, value)| {
+            key == "workDoneProgress" && value == &serde_json::Value::Bool(true)
+                || declares_progress(value)
+        }),
+        serde_json::Value::Array(values) => values.iter().any(declares_progress),
+        _ => false,
+    }
+}
+
+/// Where the content of a source comes from.
+#[derive(Clone, Copy)]
+enum Source<'a> {
+    File(&'a Path),
+    /// An unsaved buffer, at the version the editor gave it, if it did.
+    Buffer {
+        text: &'a str,
+        version: Option<i32>,
+    },
+}
+
+struct Stop;
+
+struct InferIntf;
+
+impl Request for InferIntf {
+    type Params = Vec<Url>;
+    type Result = String;
+    const METHOD: &'static str = "ocamllsp/inferIntf";
+}
+
+/// Asks `server` for the interface of the document open at `url`, as it inferred it. Nothing is
+/// formatted nor written, and nothing is waited for, so this is the building block for
+/// pipelines of one's own on top of a connection to the server. Fails with the
+/// [`async_lsp::Error`] the request failed with, `METHOD_NOT_FOUND` if the server can't infer
+/// interfaces.
+pub async fn request_infer_intf(
+    server: &mut ServerSocket,
+    url: &Url,
+) -> color_eyre::Result<String> {
+    server
+        .request::<InferIntf>(vec![url.clone()])
+        .await
+        .wrap_err("inferIntf request failed")
+}
+
+/// How the language server is reached.
+enum Transport {
+    /// By spawning it as configured.
+    Spawn,
+    /// Through a connection of one's own, e.g. to a server running in the same process.
+    Io(
+        Box<dyn AsyncRead + Send + Unpin>,
+        Box<dyn AsyncWrite + Send + Unpin>,
+    ),
+}
+
+/// A running language server.
+struct Connection {
+    server: ServerSocket,
+    // the server is killed when the connection is dropped, it's `None` when it wasn't spawned
+    child: Option<Child>,
+    mainloop_fut: JoinHandle<()>,
+    capabilities: Capabilities,
+    server_info: Option<ServerInfo>,
+    timings: StartupTimings,
+    /// How many requests were sent to the server. async-lsp 0.2 doesn't tell the ids of the
+    /// requests, its main loop numbers them from 0 in the order they're sent, so they're counted
+    /// to know which one to cancel.
+    requests: i32,
+}
+
+impl Connection {
+    /// Spawns the language server in `root_dir`, or reaches it through `transport`, and waits
+    /// for it to index the project, as configured by `config`. The diagnostics it publishes are
+    /// stored in `diagnostics`.
+    async fn start(
+        root_dir: &Path,
+        config: &InferConfig,
+        diagnostics: Diagnostics,
+        transport: Transport,
+    ) -> color_eyre::Result<Self> {
+        let command = &config.server;
+        let (indexing_tx, indexing_rx) = oneshot::channel();
+        let (indexed_tx, indexed_rx) = oneshot::channel();
+
+        let (mainloop, mut server) = async_lsp::MainLoop::new_client(|_server| {
+            let mut router = Router::new(ClientState {
+                indexing_tx: Some(indexing_tx),
+                indexed_tx: Some(indexed_tx),
+                indexing: HashSet::new(),
+                diagnostics: diagnostics.clone(),
+            });
+            router
+                .notification::<Progress>(|this, prog| {
+                    this.on_progress(prog);
+                    ControlFlow::Continue(())
+                })
+                // servers create the progress tokens before reporting progress with them
+                .request::<WorkDoneProgressCreate, _>(|_, params| {
+                    tracing::debug!("progress {:?} created", params.token);
+                    async { Ok(()) }
+                })
+                .notification::<PublishDiagnostics>(|this, params| {
+                    let document = DocumentDiagnostics {
+                        version: params.version,
+                        diagnostics: params.diagnostics,
+                    };
+                    this.diagnostics.insert(params.uri, document);
+                    ControlFlow::Continue(())
+                })
+                .notification::<ShowMessage>(|_, params| {
+                    tracing::debug!("show message: {:?}: {}", params.typ, params.message);
+                    ControlFlow::Continue(())
+                })
+                .notification::<LogMessage>(|_, params| {
+                    tracing::debug!("log message: {:?}: {}", params.typ, params.message);
+                    ControlFlow::Continue(())
+                })
+                .event(|_, _: Stop| ControlFlow::Break(Ok(())));
+
+            ServiceBuilder::new()
+                .layer(CatchUnwindLayer::default())
+                .layer(ConcurrencyLayer::default())
+                .service(router)
+        });
+
+        let started = Instant::now();
+        let (child, stdout, stdin) = match transport {
+            Transport::Spawn => {
+                let mut child = async_process::Command::new(&command.program)
+                    .args(&command.args)
+                    .current_dir(root_dir)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(server_stderr(&command.stderr)?)
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(|err| spawn_error(&command.program, err))?;
+                let stdout: Box<dyn AsyncRead + Send + Unpin> =
+                    Box::new(child.stdout.take().unwrap());
+                let stdin: Box<dyn AsyncWrite + Send + Unpin> =
+                    Box::new(child.stdin.take().unwrap());
+                (Some(child), stdout, stdin)
+            }
+            Transport::Io(input, output) => (None, input, output),
+        };
+        let spawned = Instant::now();
+
+        let mainloop_fut = tokio::spawn(async move {
+            if let Err(err) = mainloop.run_buffered(stdout, stdin).await {
+                tracing::debug!("main loop stopped: {err}");
+            }
+        });
+
+        let initialized =
+            initialize(&mut server, root_dir, config)
+                .await
+                .and_then(|initialize_result| {
+                    let capabilities = Capabilities::new(&initialize_result);
+                    tracing::debug!("negotiated {capabilities:?}");
+                    // no need to index the project for a server that said it can't infer anything
+                    if capabilities.infer_intf == Some(false) {
+                        let server_info = initialize_result.server_info.as_ref();
+                        return Err(infer_intf_unsupported(server_info));
+                    }
+                    Ok((initialize_result, capabilities))
+                });
+        let (initialize_result, capabilities) = match initialized {
+            Ok(initialized) => initialized,
+            Err(err) => {
+                let _: Result<_, _> = teardown(server, mainloop_fut, child, config).await;
+                return Err(err);
+            }
+        };
+        let initialized = Instant::now();
+
+        if capabilities.progress {
+            wait_for_indexing(indexing_rx, indexed_rx, config.index_timeout).await;
+        } else {
+            tracing::debug!("server doesn't report progress, not waiting for it to index");
+        }
+
+        Ok(Self {
+            server,
+            child,
+            mainloop_fut,
+            capabilities,
+            server_info: initialize_result.server_info,
+            timings: StartupTimings {
+                spawn: spawned - started,
+                initialize: initialized - spawned,
+                index: initialized.elapsed(),
+            },
+            // the initialize request, sent before there's a connection to count it
+            requests: 1,
+        })
+    }
+
+    /// Sends the request `R`, returning its id along with its response so it can be cancelled.
+    /// Every request but the initialize and shutdown ones goes through here, so the ids never
+    /// drift from the ones the main loop gives.
+    fn request<R: Request>(
+        &mut self,
+        params: R::Params,
+    ) -> (
+        NumberOrString,
+        impl Future<Output = async_lsp::Result<R::Result>>,
+    ) {
+        let id = NumberOrString::Number(self.requests);
+        self.requests += 1;
+        let server = self.server.clone();
+        (id, async move { server.request::<R>(params).await })
+    }
+
+    /// Tells the server to stop working on the request `id`, which is given up on, so it's free
+    /// for the next ones. Returns whether the server could be told.
+    fn cancel(&mut self, id: NumberOrString) -> bool {
+        tracing::debug!("cancelling request {id:?}");
+        match self.server.cancel_request(CancelParams { id }) {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::debug!("couldn't cancel request: {err}");
+                false
+            }
+        }
+    }
+}
+
+/// A running language server, reused to infer the interfaces of many files so the project is
+/// only indexed once. The server is restarted once if it exits in the middle of the session.
+pub struct InferSession {
+    root_dir: PathBuf,
+    /// The workspace folders are canonicalized.
+    config: InferConfig,
+    connection: Connection,
+    diagnostics: Diagnostics,
+    /// The documents opened on the server.
+    opened: HashSet<Url>,
+    /// The version of the latest document sent to the server, every document sent gets a new one
+    /// so the server never sees the same version twice.
+    version: i32,
+    restarted: bool,
+    /// Whether the server is considered unresponsive, once a request timed out and couldn't be
+    /// cancelled, or timed out again after one was.
+    timed_out: bool,
+    /// Whether the latest request that timed out was cancelled, leaving the server free for the
+    /// next files.
+    cancelled: bool,
+    /// Whether a request already timed out.
+    hung: bool,
+}
+
+impl InferSession {
+    /// Spawns the language server in `root_dir` and waits for it to index the project, as
+    /// configured by `config`. Every request to the server fails with [`TimedOut`] when it takes
+    /// longer than the configured timeout. The interfaces are only formatted when the server
+    /// supports it.
+    pub async fn start(root_dir: &Path, config: &InferConfig) -> color_eyre::Result<Self> {
+        Self::open(root_dir, config, Transport::Spawn).await
+    }
+
+    /// Starts a session like [`start`](Self::start), with a server reached through `input` and
+    /// `output` instead of the configured one, e.g. a server running in the same process. The
+    /// server isn't restarted if the connection to it breaks, and `config.server` is only used
+    /// to name it.
+    pub async fn connect(
+        root_dir: &Path,
+        config: &InferConfig,
+        input: impl AsyncRead + Send + Unpin + 'static,
+        output: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> color_eyre::Result<Self> {
+        let transport = Transport::Io(Box::new(input), Box::new(output));
+        Self::open(root_dir, config, transport).await
+    }
+
+    async fn open(
+        root_dir: &Path,
+        config: &InferConfig,
+        transport: Transport,
+    ) -> color_eyre::Result<Self> {
+        let root_dir = root_dir
+            .canonicalize()
+            .wrap_err_with(|| format!("couldn't resolve {}", root_dir.display()))?;
+        let mut config = config.clone();
+        config.workspace_folders = config
+            .workspace_folders
+            .iter()
+            .map(|folder| {
+                folder
+                    .canonicalize()
+                    .wrap_err_with(|| format!("couldn't resolve {}", folder.display()))
+            })
+            .collect::<color_eyre::Result<Vec<_>>>()?;
+        let diagnostics = Diagnostics::default();
+        let connection =
+            Connection::start(&root_dir, &config, diagnostics.clone(), transport).await?;
+
+        Ok(Self {
+            root_dir,
+            config,
+            connection,
+            diagnostics,
+            opened: HashSet::new(),
+            version: 0,
+            restarted: false,
+            timed_out: false,
+            cancelled: false,
+            hung: false,
+        })
+    }
+
+    /// Infers the interface of the implementation `file` without writing anything to disk.
+    /// `dest` is where the interface is meant to be written, usually the
+    /// [`interface_path`](crate::interface_path) of `file`, the doc comments of the interface
+    /// already there are carried over and it's formatted as that file. Both are resolved against
+    /// the root directory.
+    ///
+    /// Inferring a file again sends its new content to the server, so it can be called after the
+    /// file changed. A request that times out is cancelled, so the next files are inferred by the
+    /// same server, but once another one times out, or the first one couldn't be cancelled, the
+    /// server is considered unresponsive and every file is skipped.
+    pub async fn infer(&mut self, file: &Path, dest: &Path) -> color_eyre::Result<Inference> {
+        self.infer_from(file, file, dest).await
+    }
+
+    /// Infers the interface of `file` like [`infer`](Self::infer), but with the content of
+    /// `source`. The server is told the content is the one of `file`, which doesn't have to
+    /// exist, so it resolves the modules of the project for a source stored anywhere.
+    pub async fn infer_from(
+        &mut self,
+        file: &Path,
+        source: &Path,
+        dest: &Path,
+    ) -> color_eyre::Result<Inference> {
+        let source = self.root_dir.join(source);
+        self.infer_source(file, Source::File(&source), dest).await
+    }
+
+    /// Infers the interface of `file` like [`infer`](Self::infer), but with the content of an
+    /// unsaved buffer, `text` at `version`. The server is told it's the content of `file`, which
+    /// doesn't have to exist, and `version` is sent as is. Without `version`, the buffer gets the
+    /// next version of the session, like the files do.
+    pub async fn infer_buffer(
+        &mut self,
+        file: &Path,
+        text: &str,
+        version: Option<i32>,
+        dest: &Path,
+    ) -> color_eyre::Result<Inference> {
+        self.infer_source(file, Source::Buffer { text, version }, dest)
+            .await
+    }
+
+    #[tracing::instrument(skip(self, source, dest), fields(file = %file.display()))]
+    async fn infer_source(
+        &mut self,
+        file: &Path,
+        source: Source<'_>,
+        dest: &Path,
+    ) -> color_eyre::Result<Inference> {
+        if self.timed_out {
+            return Err(eyre!("skipped {}, the server timed out", file.display()));
+        }
+
+        let path = self.root_dir.join(file);
+        let dest = self.root_dir.join(dest);
+        let mut result = self.infer_file(&path, source, &dest).await;
+        if !self.restarted {
+            if let Some(exited) = self.exited(&result).await {
+                tracing::warn!("{exited}, restarting it");
+                self.restarted = true;
+                result = match self.restart().await {
+                    Ok(()) => self.infer_file(&path, source, &dest).await,
+                    Err(err) => Err(err.wrap_err(format!("{exited}, and couldn't restart it"))),
+                };
+            }
+        }
+        if let Some(exited) = self.exited(&result).await {
+            result = Err(exited.into());
+        }
+
+        let result =
+            result.wrap_err_with(|| format!("couldn't infer interface for {}", file.display()));
+        if result
+            .as_ref()
+            .is_err_and(|err| err.downcast_ref::<TimedOut>().is_some())
+        {
+            self.timed_out = !self.cancelled || self.hung;
+            self.hung = true;
+        }
+        self.cancelled = false;
+        let (mut interface, formatted, timings) = result?;
+        if let Some(names) = &self.config.only {
+            interface = select_declarations(&interface, names);
+        }
+        let mut stripped = Vec::new();
+        if self.config.strip_includes {
+            stripped.push("include");
+        }
+        if self.config.strip_opens {
+            stripped.extend(["open", "open!"]);
+        }
+        if !stripped.is_empty() {
+            interface = strip_lines(&interface, &stripped);
+        }
+        if self.config.append_to_existing {
+            if let Ok(existing) = read_text(&dest, self.config.encoding) {
+                interface = append_missing(&existing, &interface);
+            }
+        }
+        Ok(Inference {
+            interface: end_lines(&interface, self.config.final_newline),
+            diagnostics: self.diagnostics(file),
+            formatted,
+            timings,
+        })
+    }
+
+    /// The name and version the server reported on initialize, if it did.
+    pub fn server_info(&self) -> Option<&ServerInfo> {
+        self.connection.server_info.as_ref()
+    }
+
+    /// What the server agreed to on initialize.
+    pub fn capabilities(&self) -> Capabilities {
+        self.connection.capabilities
+    }
+
+    /// How long starting the server took, the latest one when it was restarted.
+    pub fn startup_timings(&self) -> StartupTimings {
+        self.connection.timings
+    }
+
+    /// The latest diagnostics the server published for `file`, resolved against the root
+    /// directory.
+    pub fn diagnostics(&self, file: &Path) -> Vec<Diagnostic> {
+        let Some(url) = canonicalize(&self.root_dir.join(file))
+            .ok()
+            .and_then(|path| document_url(&path, &self.config).ok())
+        else {
+            return Vec::new();
+        };
+        let documents = self.diagnostics.documents.lock().unwrap();
+        documents
+            .get(&url)
+            .map(|document| document.diagnostics.clone())
+            .unwrap_or_default()
+    }
+
+    /// Waits for the server to publish the diagnostics of the document at `url` for `version`,
+    /// until it doesn't seem to publish them.
+    async fn wait_for_diagnostics(&self, url: &Url, version: i32) {
+        if !self.connection.capabilities.diagnostics
+            && !self.diagnostics.any.load(Ordering::Relaxed)
+        {
+            tracing::debug!("server doesn't publish diagnostics, not waiting for them");
+            return;
+        }
+        let published = async {
+            loop {
+                // created before checking, so a publish right after isn't missed
+                let notified = self.diagnostics.notify.notified();
+                if self.diagnostics.published(url, version) {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        if timeout(DIAGNOSTICS_TIMEOUT, published).await.is_err() {
+            tracing::debug!("server didn't publish diagnostics for {url} at version {version}");
+        }
+    }
+
+    /// Shuts the language server down and waits for it to exit.
+    pub async fn shutdown(self) -> color_eyre::Result<()> {
+        teardown(
+            self.connection.server,
+            self.connection.mainloop_fut,
+            self.connection.child,
+            &self.config,
+        )
+        .await
+    }
+
+    /// Whether the server exited, when `result` failed because the connection to it broke.
+    async fn exited<T>(&mut self, result: &color_eyre::Result<T>) -> Option<ServerExited> {
+        let err = result.as_ref().err()?;
+        let broken = matches!(
+            err.downcast_ref::<async_lsp::Error>(),
+            Some(
+                async_lsp::Error::ServiceStopped | async_lsp::Error::Eof | async_lsp::Error::Io(_)
+            )
+        );
+        if !broken {
+            return None;
+        }
+
+        // the pipes may be closed a little before the process exits
+        let child = self.connection.child.as_mut()?;
+        let status = timeout(EXIT_TIMEOUT, child.status()).await.ok()?.ok()?;
+        Some(ServerExited {
+            program: self.config.server.program.clone(),
+            status,
+        })
+    }
+
+    /// Starts a new server in place of the one that exited, the documents have to be opened
+    /// again on it.
+    async fn restart(&mut self) -> color_eyre::Result<()> {
+        self.opened.clear();
+        self.connection = Connection::start(
+            &self.root_dir,
+            &self.config,
+            self.diagnostics.clone(),
+            Transport::Spawn,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn infer_file(
+        &mut self,
+        file: &Path,
+        source: Source<'_>,
+        dest: &Path,
+    ) -> color_eyre::Result<(String, bool, Timings)> {
+        let (path, text, version) = match source {
+            Source::File(source) => {
+                let metadata = std::fs::metadata(source).wrap_err("couldn't read file")?;
+                if !metadata.is_file() {
+                    return Err(eyre!("not a file"));
+                }
+                let path =
+                    check_source(&self.root_dir, file, metadata.len(), self.config.max_size)?;
+                let text =
+                    read_text(source, self.config.encoding).wrap_err("couldn't read file")?;
+                (path, text, None)
+            }
+            Source::Buffer { text, version } => {
+                let size = text.len() as u64;
+                let path = check_source(&self.root_dir, file, size, self.config.max_size)?;
+                (path, text.to_string(), version)
+            }
+        };
+        // the same URL for opening and inferring, or the server wouldn't find the document
+        let url = document_url(&path, &self.config)?;
+        let version = self.sync_file(&url, file, &text, version, true)?;
+        let inferred = self.infer_intf(&url, file, dest, &text).await?;
+        self.wait_for_diagnostics(&url, version).await;
+        Ok(inferred)
+    }
+
+    /// The language of `file`, detected from its extension unless one was given.
+    fn language_id(&self, file: &Path) -> String {
+        if let Some(language_id) = &self.config.language_id {
+            return language_id.clone();
+        }
+        match file.extension().and_then(OsStr::to_str) {
+            Some("re" | "rei") => "reason".into(),
+            _ => "ocaml".into(),
+        }
+    }
+
+    /// Opens `file` on the server at `url` with `text`, or sends the new `text` when it's already
+    /// open, at `version` or the next one, which is returned. Saving tells the server the file
+    /// changed on disk too, so it rebuilds the project. A file just opened is only saved when
+    /// configured to, for servers that need it. The diagnostics of the previous text are
+    /// forgotten.
+    fn sync_file(
+        &mut self,
+        url: &Url,
+        file: &Path,
+        text: &str,
+        version: Option<i32>,
+        save: bool,
+    ) -> color_eyre::Result<i32> {
+        let url = url.clone();
+        let version = version.unwrap_or_else(|| {
+            self.version += 1;
+            self.version
+        });
+        self.diagnostics.documents.lock().unwrap().remove(&url);
+        if self.opened.insert(url.clone()) {
+            let language_id = self.language_id(file);
+            open_file(
+                &mut self.connection.server,
+                url.clone(),
+                language_id,
+                version,
+                text,
+            )?;
+            if !self.config.save_before_infer {
+                return Ok(version);
+            }
+        } else {
+            self.connection
+                .server
+                .did_change(DidChangeTextDocumentParams {
+                    text_document: VersionedTextDocumentIdentifier {
+                        uri: url.clone(),
+                        version,
+                    },
+                    content_changes: vec![TextDocumentContentChangeEvent {
+                        range: None,
+                        range_length: None,
+                        text: text.into(),
+                    }],
+                })
+                .wrap_err("couldn't update file")?;
+        }
+        if !save {
+            return Ok(version);
+        }
+
+        let text = self.connection.capabilities.save_text.then(|| text.into());
+        self.connection
+            .server
+            .did_save(DidSaveTextDocumentParams {
+                text_document: TextDocumentIdentifier { uri: url },
+                text,
+            })
+            .wrap_err("couldn't save file")?;
+        Ok(version)
+    }
+
+    fn close_file(&mut self, url: Url) -> color_eyre::Result<()> {
+        self.opened.remove(&url);
+        self.connection
+            .server
+            .did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier { uri: url },
+            })
+            .wrap_err("couldn't close file")
+    }
+
+    /// Infers the interface of `file`, open at `url`, to be written to `dest`, whether it could
+    /// be formatted and how long it took.
+    async fn infer_intf(
+        &mut self,
+        url: &Url,
+        file: &Path,
+        dest: &Path,
+        source: &str,
+    ) -> color_eyre::Result<(String, bool, Timings)> {
+        debug_assert!(
+            self.opened.contains(url),
+            "inferring {url}, which isn't open"
+        );
+        let mut timings = Timings::default();
+        let started = Instant::now();
+        let mut attempt = 0;
+        let text = loop {
+            tracing::debug!("inferring {} (attempt {})", file.display(), attempt + 1);
+            let (id, infer) = self.connection.request::<InferIntf>(vec![url.clone()]);
+            let result =
+                with_timeout(self.config.request_timeout, "inferIntf request", infer).await;
+            if result.is_err() {
+                self.cancelled = self.connection.cancel(id);
+            }
+            let text = match result?.wrap_err("inferIntf request failed") {
+                Err(err)
+                    if matches!(
+                        err.downcast_ref(),
+                        Some(async_lsp::Error::Response(err))
+                            if err.code == ErrorCode::METHOD_NOT_FOUND
+                    ) =>
+                {
+                    return Err(infer_intf_unsupported(self.connection.server_info.as_ref()));
+                }
+                result => result?,
+            };
+
+            // an empty interface for a non-empty source usually means the server wasn't done
+            // indexing
+            if !text.trim().is_empty() || source.trim().is_empty() || attempt == self.config.retries
+            {
+                break text;
+            }
+            attempt += 1;
+            tracing::debug!("got an empty interface for {}, retrying", file.display());
+            sleep(RETRY_DELAY).await;
+        };
+        timings.infer = started.elapsed();
+
+        // before formatting, so the comments are formatted along with the rest
+        let text = match read_text(dest, self.config.encoding) {
+            Ok(old) if self.config.merge_docs => merge_doc_comments(&old, &text),
+            _ => text,
+        };
+
+        let Some(mut formatting) = self.config.formatting.clone() else {
+            return Ok((text, false, timings));
+        };
+
+        if !self.connection.capabilities.formatting {
+            tracing::debug!("the server can't format, skipping formatting");
+            return Ok((text, false, timings));
+        }
+
+        if self.config.ocamlformat {
+            let dir = file.parent().unwrap_or(file);
+            let options = ocamlformat_options(dir, &self.root_dir);
+            formatting.properties.extend(options);
+        }
+
+        let started = Instant::now();
+        // format a document that only exists on the server, so it never mistakes the interface
+        // for the content of `dest`, which may not exist yet or hold the previous interface
+        let scratch = scratch_path(dest);
+        let mli_url = document_url(&scratch, &self.config)?;
+        self.sync_file(&mli_url, &scratch, &text, None, false)?;
+
+        // format the mli file
+        let (id, formatting) = self
+            .connection
+            .request::<Formatting>(DocumentFormattingParams {
+                text_document: TextDocumentIdentifier {
+                    uri: mli_url.clone(),
+                },
+                options: formatting,
+                work_done_progress_params: Default::default(),
+            });
+        let format_result =
+            with_timeout(self.config.format_timeout, "formatting request", formatting).await;
+        if format_result.is_err() {
+            // formatting is given up on without failing the inference, so it doesn't matter
+            // whether the server could be told
+            self.connection.cancel(id);
+        }
+
+        // the document was only opened to be formatted, close it whether formatting worked or not
+        let closed = self.close_file(mli_url);
+        timings.format = started.elapsed();
+        let format_result = match format_result {
+            Ok(format_result) => format_result,
+            // formatting is only a nicety, so a slow formatter doesn't fail the inference
+            Err(err) => {
+                tracing::warn!("{err}, leaving {} unformatted", dest.display());
+                closed?;
+                return Ok((text, false, timings));
+            }
+        };
+        closed?;
+
+        // check if the formatting was successful
+        match format_result {
+            // no edits too, logged apart so a server that never formats can be told apart
+            Ok(None) => {
+                tracing::debug!("formatting returned null for {}", dest.display());
+                Ok((text, true, timings))
+            }
+            Ok(Some(edits)) if edits.is_empty() => {
+                tracing::debug!(
+                    "formatting returned no edits, {} is formatted",
+                    dest.display()
+                );
+                Ok((text, true, timings))
+            }
+            Ok(Some(edits)) => {
+                let mut rope = Rope::from_str(&text);
+                let encoding = self
+                    .config
+                    .position_encoding
+                    .unwrap_or(self.connection.capabilities.encoding);
+                let summary = apply_edits(&mut rope, &edits, encoding);
+                tracing::debug!(
+                    "formatting applied {} of {} edits, {:+} bytes",
+                    summary.edits,
+                    edits.len(),
+                    summary.bytes_added
+                );
+                Ok((rope.to_string(), true, timings))
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "couldn't format {}, leaving it unformatted: {err}",
+                    dest.display()
+                );
+                Ok((text, false, timings))
+            }
+        }
+    }
+}
+
+/// `text` ending with exactly one newline, or none at all without `final_newline`, whatever the
+/// server and formatting ended it with.
+fn end_lines(text: &str, final_newline: bool) -> String {
+    let text = text.trim_end();
+    if final_newline && !text.is_empty() {
+        format!("{text}\n")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Checks that `file` is an implementation file, which interfaces are inferred from, within the
+/// canonical `root_dir`, and that its content of `size` bytes is no larger than `max_size`.
+/// Returns the canonical path of `file`.
+fn check_source(
+    root_dir: &Path,
+    file: &Path,
+    size: u64,
+    max_size: Option<u64>,
+) -> color_eyre::Result<PathBuf> {
+    // the server only knows about the files of its workspace folder
+    let canonical = canonicalize(file).wrap_err("couldn't resolve file")?;
+    if !canonical.starts_with(root_dir) {
+        return Err(eyre!(
+            "it's outside of the project root {}",
+            root_dir.display()
+        ));
+    }
+    match file.extension().and_then(OsStr::to_str) {
+        Some("ml" | "re") => {}
+        Some("mli" | "rei") => {
+            return Err(eyre!(
+            "it's an interface already, interfaces are inferred from the .ml implementation file"
+        ))
+        }
+        _ => return Err(eyre!("not an .ml file")),
+    }
+    // huge generated sources take a lot of memory and time to infer and format
+    if let Some(max_size) = max_size.filter(|&max_size| size > max_size) {
+        return Err(eyre!(
+            "it's {size} bytes, more than the maximum of {max_size} bytes"
+        ));
+    }
+    Ok(canonical)
+}
+
+/// `file` with its symlinks and `.` segments resolved, so it's always the same URL to the
+/// server. `file` itself only exists when it's the source, otherwise it's resolved from its
+/// parent.
+fn canonicalize(file: &Path) -> std::io::Result<PathBuf> {
+    file.canonicalize()
+        .or_else(|err| match (file.parent(), file.file_name()) {
+            (Some(parent), Some(name)) => Ok(parent.canonicalize()?.join(name)),
+            _ => Err(err),
+        })
+}
+
+/// The error of `program` that couldn't be run, with a hint at what to do about it.
+fn spawn_error(program: &str, err: std::io::Error) -> Report {
+    match err.kind() {
+        std::io::ErrorKind::NotFound if program == "ocamllsp" => eyre!(
+            "ocamllsp wasn't found on the PATH, install it with `opam install ocaml-lsp-server` \
+             or run it from where it's installed"
+        ),
+        std::io::ErrorKind::NotFound => eyre!("{program} wasn't found"),
+        std::io::ErrorKind::PermissionDenied => {
+            eyre!("{program} can't be run, check that it's executable")
+        }
+        _ => Report::new(err).wrap_err(format!("couldn't run {program}")),
+    }
+}
+
+/// The error of a server that can't infer interfaces, named as it reported itself.
+fn infer_intf_unsupported(server_info: Option<&ServerInfo>) -> Report {
+    let name = match server_info {
+        Some(ServerInfo {
+            name,
+            version: Some(version),
+        }) => format!("{name} {version}"),
+        Some(ServerInfo {
+            name,
+            version: None,
+        }) => name.clone(),
+        None => "the server".into(),
+    };
+    eyre!("{name} doesn't support inferring interfaces, it's either too old or not ocamllsp")
+}
+
+async fn with_timeout<F: Future>(
+    request_timeout: Option<Duration>,
+    phase: &'static str,
+    fut: F,
+) -> color_eyre::Result<F::Output> {
+    match request_timeout {
+        Some(after) => timeout(after, fut)
+            .await
+            .map_err(|_| TimedOut { phase, after }.into()),
+        None => Ok(fut.await),
+    }
+}
+
+/// The workspace folder at `path`, named after it so the server can tell the folders apart in
+/// its logs.
+fn workspace_folder(path: &Path, config: &InferConfig) -> color_eyre::Result<WorkspaceFolder> {
+    Ok(WorkspaceFolder {
+        uri: document_url(path, config)?,
+        name: path
+            .file_name()
+            .map_or("root".into(), |name| name.to_string_lossy().into_owned()),
+    })
+}
+
+async fn initialize(
+    server: &mut ServerSocket,
+    root_dir: &Path,
+    config: &InferConfig,
+) -> color_eyre::Result<InitializeResult> {
+    let folders = std::iter::once(root_dir)
+        .chain(config.workspace_folders.iter().map(PathBuf::as_path))
+        .map(|folder| workspace_folder(folder, config))
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+
+    let root_uri = config
+        .root_uri
+        .unwrap_or(config.workspace_folders.is_empty());
+    #[allow(deprecated)]
+    let initialize = server.initialize(InitializeParams {
+        root_uri: root_uri.then(|| folders[0].uri.clone()),
+        root_path: root_uri.then(|| root_dir.to_string_lossy().into_owned()),
+        workspace_folders: Some(folders),
+        capabilities: ClientCapabilities {
+            window: Some(WindowClientCapabilities {
+                work_done_progress: Some(true),
+                ..WindowClientCapabilities::default()
+            }),
+            general: Some(GeneralClientCapabilities {
+                position_encodings: Some(PositionEncoding::supported()),
+                ..GeneralClientCapabilities::default()
+            }),
+            ..ClientCapabilities::default()
+        },
+        ..InitializeParams::default()
+    });
+    let initialize_result = with_timeout(config.request_timeout, "initialize", initialize)
+        .await?
+        .wrap_err("couldn't initialize")?;
+
+    server
+        .initialized(InitializedParams {})
+        .wrap_err("couldn't initialize")?;
+    if let Some(settings) = &config.server.settings {
+        server
+            .did_change_configuration(DidChangeConfigurationParams {
+                settings: settings.clone(),
+            })
+            .wrap_err("couldn't configure the server")?;
+    }
+
+    Ok(initialize_result)
+}
+
+/// Shuts the server down, and kills it when it doesn't exit in time so it never outlives its
+/// session. Every way a connection ends goes through here, so none of the steps is forgotten.
+async fn teardown(
+    mut server: ServerSocket,
+    mainloop_fut: JoinHandle<()>,
+    child: Option<Child>,
+    config: &InferConfig,
+) -> color_eyre::Result<()> {
+    let result = with_timeout(config.request_timeout, "shutdown", server.shutdown(()))
+        .await
+        .and_then(|result| result.wrap_err("couldn't shutdown"));
+    if result.is_ok() {
+        server.exit(()).wrap_err("couldn't exit")?;
+    }
+
+    // stop the main loop even if the server didn't acknowledge the shutdown, it's killed when
+    // dropped anyway. The main loop has already stopped if the server exited
+    let _: Result<_, _> = server.emit(Stop);
+    mainloop_fut.await.wrap_err("couldn't finish main loop")?;
+
+    let Some(mut child) = child else {
+        return result;
+    };
+    if timeout(config.kill_timeout, child.status()).await.is_err() {
+        tracing::warn!(
+            "{} didn't exit after {}s, killing it",
+            config.server.program,
+            config.kill_timeout.as_secs()
+        );
+        child.kill().wrap_err("couldn't kill the server")?;
+        // reaped, so it's not left a zombie
+        let _: Result<_, _> = child.status().await;
+    }
+
+    result
+}
+
+async fn wait_for_indexing(
+    indexing_rx: oneshot::Receiver<()>,
+    indexed_rx: oneshot::Receiver<()>,
+    index_timeout: Option<Duration>,
+) {
+    if timeout(PROGRESS_START_TIMEOUT, indexing_rx).await.is_err() {
+        tracing::debug!("server didn't report any progress, assuming it's ready");
+        return;
+    }
+
+    tracing::debug!("waiting for the server to finish indexing");
+    let Some(index_timeout) = index_timeout else {
+        let _: Result<_, _> = indexed_rx.await;
+        return;
+    };
+    if timeout(index_timeout, indexed_rx).await.is_err() {
+        tracing::warn!(
+            "server didn't finish indexing after {}s, inferring anyway",
+            index_timeout.as_secs()
+        );
+    }
+}
+
+/// The path of the document `dest` is formatted as, next to it so the server finds the same
+/// formatting configuration, and with the same extension so it's formatted as an interface.
+fn scratch_path(dest: &Path) -> PathBuf {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy();
+    dest.with_file_name(format!(".infer-mli.{name}"))
+}
+
+/// Where the error output of the server goes, as asked by `stderr`.
+fn server_stderr(stderr: &ServerStderr) -> color_eyre::Result<Stdio> {
+    Ok(match stderr {
+        ServerStderr::Inherit => Stdio::inherit(),
+        ServerStderr::Null => Stdio::null(),
+        ServerStderr::File(path) => std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("couldn't open {}", path.display()))?
+            .into(),
+    })
+}
+
+/// The URL of the document or folder at the absolute `path`, the only place URLs are made so
+/// they're always the same ones. It's a `file://` URL unless `config` has a template for them.
+fn document_url(path: &Path, config: &InferConfig) -> color_eyre::Result<Url> {
+    let url = Url::from_file_path(path)
+        .map_err(|()| eyre!("{} isn't an absolute path", path.display()))?;
+    let Some(template) = &config.uri_template else {
+        return Ok(url);
+    };
+    Url::parse(&template.replace("{path}", url.path()))
+        .wrap_err_with(|| format!("{template} doesn't make a valid URL"))
+}
+
+fn open_file(
+    socket: &mut ServerSocket,
+    url: Url,
+    language_id: String,
+    version: i32,
+    text: &str,
+) -> color_eyre::Result<()> {
+    socket
+        .did_open(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: url,
+                language_id,
+                version,
+                text: text.into(),
+            },
+        })
+        .wrap_err("couldn't open file")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use lsp_types::{WorkDoneProgressBegin, WorkDoneProgressEnd};
+
+    use super::*;
+
+    fn begin(token: &str) -> ProgressParams {
+        ProgressParams {
+            token: NumberOrString::String(token.into()),
+            value: ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(
+                WorkDoneProgressBegin::default(),
+            )),
+        }
+    }
+
+    fn end(token: &str) -> ProgressParams {
+        ProgressParams {
+            token: NumberOrString::String(token.into()),
+            value: ProgressParamsValue::WorkDone(WorkDoneProgress::End(
+                WorkDoneProgressEnd::default(),
+            )),
+        }
+    }
+
+    /// A client state, with the receivers told when indexing starts and when it's done.
+    fn client_state() -> (ClientState, oneshot::Receiver<()>, oneshot::Receiver<()>) {
+        let (indexing_tx, indexing_rx) = oneshot::channel();
+        let (indexed_tx, indexed_rx) = oneshot::channel();
+        let state = ClientState {
+            indexing_tx: Some(indexing_tx),
+            indexed_tx: Some(indexed_tx),
+            indexing: HashSet::new(),
+            diagnostics: Diagnostics::default(),
+        };
+        (state, indexing_rx, indexed_rx)
+    }
+
+    #[test]
+    fn ignores_other_progress() {
+        let (mut state, mut indexing_rx, mut indexed_rx) = client_state();
+        state.on_progress(begin("merlin"));
+        state.on_progress(end("merlin"));
+        assert_eq!(indexing_rx.try_recv(), Ok(None));
+        assert_eq!(indexed_rx.try_recv(), Ok(None));
+    }
+
+    #[test]
+    fn waits_for_every_build_to_end() {
+        let (mut state, mut indexing_rx, mut indexed_rx) = client_state();
+        state.on_progress(begin("dune-build-1"));
+        assert_eq!(indexing_rx.try_recv(), Ok(Some(())));
+        state.on_progress(begin("dune-build-2"));
+        state.on_progress(end("dune-build-1"));
+        assert_eq!(indexed_rx.try_recv(), Ok(None));
+        state.on_progress(end("dune-build-2"));
+        assert_eq!(indexed_rx.try_recv(), Ok(Some(())));
+    }
+
+    fn publish(published: &Published, url: &Url, version: Option<i32>) {
+        let document = DocumentDiagnostics {
+            version,
+            diagnostics: Vec::new(),
+        };
+        published.insert(url.clone(), document);
+    }
+
+    #[test]
+    fn diagnostics_are_for_the_version_sent() {
+        let url = Url::parse("file:///foo.ml").unwrap();
+        let published = Published::default();
+        assert!(!published.published(&url, 2));
+        publish(&published, &url, Some(1));
+        assert!(!published.published(&url, 2));
+        publish(&published, &url, Some(2));
+        assert!(published.published(&url, 2));
+        // once the server said its versions, diagnostics without one may be stale
+        publish(&published, &url, None);
+        assert!(!published.published(&url, 2));
+    }
+
+    #[test]
+    fn diagnostics_without_versions_count_for_servers_never_saying_them() {
+        let url = Url::parse("file:///foo.ml").unwrap();
+        let published = Published::default();
+        publish(&published, &url, None);
+        assert!(published.published(&url, 2));
+    }
+}
@@ -0,0 +1,37 @@
+use std::process::{Command, Stdio};
+
+/// The exit status and stderr of running infer-mli with `args` and an empty stdin.
+fn run(args: &[&str]) -> (Option<i32>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_infer-mli"))
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    (
+        output.status.code(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+#[test]
+fn stdin_is_only_read_once() {
+    for args in [
+        &["--stdin", "--only", "-"][..],
+        &["--file", "-", "--only", "-"],
+        &["--serve", "--only", "-"],
+        &["--request", "-", "--only", "-"],
+        &["--stdin", "--files-from", "-"],
+        &["--file", "-", "--files-from", "-"],
+    ] {
+        let (status, stderr) = run(args);
+        assert_eq!(status, Some(2), "{args:?}: {stderr}");
+        assert!(stderr.contains("both read stdin"), "{args:?}: {stderr}");
+    }
+}
+
+#[test]
+fn compare_with_prints_no_json() {
+    let (status, stderr) = run(&["--compare-with", "a.mli", "--format", "json", "-f", "a.ml"]);
+    assert_eq!(status, Some(2), "{stderr}");
+    assert!(stderr.contains("--compare-with"), "{stderr}");
+}
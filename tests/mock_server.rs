@@ -0,0 +1,544 @@
+//! Runs the whole pipeline against a mock language server running in the same process, so it's
+//! tested without an `ocamllsp` install.
+
+use std::collections::HashMap;
+use std::io;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use async_lsp::router::Router;
+use async_lsp::{ClientSocket, ErrorCode, MainLoop, ResponseError};
+use infer_mli::lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument, Exit,
+    Initialized, PublishDiagnostics,
+};
+use infer_mli::lsp_types::request::{Formatting, Initialize, Request, Shutdown};
+use infer_mli::lsp_types::{
+    Diagnostic, DiagnosticSeverity, InitializeResult, OneOf, Position, PublishDiagnosticsParams,
+    Range, ServerCapabilities, ServerInfo, TextEdit, Url,
+};
+use infer_mli::{InferConfig, InferOutcome, InferSession, MliStatus, TimedOut};
+
+enum InferIntf {}
+
+impl Request for InferIntf {
+    type Params = Vec<Url>;
+    type Result = String;
+    const METHOD: &'static str = "ocamllsp/inferIntf";
+}
+
+/// A server inferring `val <name>  :  int` for every `let <name>` of a source, with its spaces
+/// doubled and the blank lines of the source kept so formatting has something to fix, and
+/// publishing an error for every line starting with `error`, a little after the source was sent
+/// like ocamllsp does. It never answers for sources with a line starting with `hang`.
+struct Mock {
+    client: ClientSocket,
+    documents: HashMap<Url, String>,
+    options: MockOptions,
+}
+
+#[derive(Clone, Copy)]
+struct MockOptions {
+    infer_intf: bool,
+    formatting: FormatResponse,
+    request_timeout: Duration,
+}
+
+impl Default for MockOptions {
+    fn default() -> Self {
+        Self {
+            infer_intf: true,
+            formatting: FormatResponse::Edits,
+            request_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How the mock answers formatting requests.
+#[derive(Clone, Copy)]
+enum FormatResponse {
+    /// `null`, which servers answer when they have nothing to change too.
+    Null,
+    NoEdits,
+    Edits,
+}
+
+fn infer(source: &str) -> String {
+    source
+        .lines()
+        .filter_map(|line| match line.strip_prefix("let ") {
+            Some(rest) => rest
+                .split_whitespace()
+                .next()
+                .map(|name| format!("val {name}  :  int\n")),
+            None => line.trim().is_empty().then(|| "\n".into()),
+        })
+        .collect()
+}
+
+fn edit(start: (u32, u32), end: (u32, u32), new_text: &str) -> TextEdit {
+    TextEdit {
+        range: Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1)),
+        new_text: new_text.into(),
+    }
+}
+
+/// The edits removing the blank lines, from the end of the line before them, and replacing the
+/// runs of spaces with one, their positions counted in UTF-16 code units.
+fn format(text: &str) -> Vec<TextEdit> {
+    let lines = text.lines().collect::<Vec<_>>();
+    let mut edits = Vec::new();
+    for (line, content) in lines.iter().enumerate() {
+        let line = line as u32;
+        if content.is_empty() {
+            if let Some(previous) = line.checked_sub(1) {
+                let end = lines[previous as usize].encode_utf16().count() as u32;
+                edits.push(edit((previous, end), (line, 0), ""));
+            }
+            continue;
+        }
+        let mut character = 0;
+        let mut spaces = None;
+        for ch in content.chars() {
+            if ch == ' ' {
+                spaces.get_or_insert(character);
+            } else if let Some(start) = spaces.take() {
+                if character - start > 1 {
+                    edits.push(edit((line, start), (line, character), " "));
+                }
+            }
+            character += ch.len_utf16() as u32;
+        }
+    }
+    edits
+}
+
+fn publish_diagnostics(client: &ClientSocket, uri: Url, text: &str, version: Option<i32>) {
+    let diagnostics = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.starts_with("error"))
+        .map(|(line, _)| Diagnostic {
+            range: Range::new(Position::new(line as u32, 0), Position::new(line as u32, 5)),
+            severity: Some(DiagnosticSeverity::ERROR),
+            message: "mock error".into(),
+            ..Diagnostic::default()
+        })
+        .collect();
+    let client = client.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let _ = client.notify::<PublishDiagnostics>(PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version,
+        });
+    });
+}
+
+/// Starts the mock server, returning the session connected to it.
+async fn connect(root_dir: &Path, options: MockOptions) -> color_eyre::Result<InferSession> {
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let (mainloop, _) = MainLoop::new_server(|client| {
+        let mut router = Router::new(Mock {
+            client,
+            documents: HashMap::new(),
+            options,
+        });
+        router
+            .request::<Initialize, _>(|this, _| {
+                let capabilities = ServerCapabilities {
+                    document_formatting_provider: Some(OneOf::Left(true)),
+                    experimental: Some(serde_json::json!({
+                        "ocamllsp": { "handleInferIntf": this.options.infer_intf }
+                    })),
+                    ..ServerCapabilities::default()
+                };
+                async move {
+                    Ok(InitializeResult {
+                        capabilities,
+                        server_info: Some(ServerInfo {
+                            name: "mock".into(),
+                            version: None,
+                        }),
+                    })
+                }
+            })
+            .notification::<Initialized>(|_, _| ControlFlow::Continue(()))
+            .notification::<DidOpenTextDocument>(|this, params| {
+                let document = params.text_document;
+                publish_diagnostics(&this.client, document.uri.clone(), &document.text, None);
+                this.documents.insert(document.uri, document.text);
+                ControlFlow::Continue(())
+            })
+            .notification::<DidChangeTextDocument>(|this, mut params| {
+                if let Some(change) = params.content_changes.pop() {
+                    let document = params.text_document;
+                    let version = Some(document.version);
+                    publish_diagnostics(&this.client, document.uri.clone(), &change.text, version);
+                    this.documents.insert(document.uri, change.text);
+                }
+                ControlFlow::Continue(())
+            })
+            .notification::<DidSaveTextDocument>(|_, _| ControlFlow::Continue(()))
+            .notification::<DidCloseTextDocument>(|this, params| {
+                this.documents.remove(&params.text_document.uri);
+                ControlFlow::Continue(())
+            })
+            .request::<InferIntf, _>(|this, params| {
+                let source = this.documents.get(&params[0]).cloned();
+                async move {
+                    let source = source.ok_or_else(|| {
+                        ResponseError::new(ErrorCode::INVALID_PARAMS, "document not open")
+                    })?;
+                    if source.lines().any(|line| line.starts_with("hang")) {
+                        std::future::pending::<()>().await;
+                    }
+                    Ok(infer(&source))
+                }
+            })
+            .request::<Formatting, _>(|this, params| {
+                let text = this.documents.get(&params.text_document.uri).cloned();
+                let response = this.options.formatting;
+                async move {
+                    Ok(match response {
+                        FormatResponse::Null => None,
+                        FormatResponse::NoEdits => Some(Vec::new()),
+                        FormatResponse::Edits => text.map(|text| format(&text)),
+                    })
+                }
+            })
+            .request::<Shutdown, _>(|_, _| async { Ok(()) })
+            .notification::<Exit>(|_, _| ControlFlow::Break(Ok(())));
+        router
+    });
+
+    let (input, output) = tokio::io::split(server_io);
+    tokio::spawn(mainloop.run_buffered(Compat(input), Compat(output)));
+    let (input, output) = tokio::io::split(client_io);
+    InferSession::connect(
+        root_dir,
+        &InferConfig::builder()
+            .timeout(options.request_timeout)
+            .build(),
+        Compat(input),
+        Compat(output),
+    )
+    .await
+}
+
+/// Tokio's pipes as the `futures` ones the language server protocol is spoken over.
+struct Compat<T>(T);
+
+impl<T: tokio::io::AsyncRead + Unpin> futures::AsyncRead for Compat<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = tokio::io::ReadBuf::new(buf);
+        ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+impl<T: tokio::io::AsyncWrite + Unpin> futures::AsyncWrite for Compat<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// A project of its own for the test `name`, holding `files`.
+fn project(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root_dir = std::env::temp_dir().join(format!("infer-mli-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root_dir);
+    std::fs::create_dir_all(&root_dir).unwrap();
+    std::fs::write(root_dir.join("dune-project"), "(lang dune 3.0)\n").unwrap();
+    for (path, content) in files {
+        std::fs::write(root_dir.join(path), content).unwrap();
+    }
+    root_dir
+}
+
+#[tokio::test]
+async fn infers_and_formats() {
+    let root_dir = project("infers", &[("foo.ml", "let x = 1\nlet y = 2\n")]);
+    let mut session = connect(&root_dir, MockOptions::default()).await.unwrap();
+
+    let inference = session
+        .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+        .await
+        .unwrap();
+    assert_eq!(inference.interface, "val x : int\nval y : int\n");
+    assert!(inference.formatted);
+    assert!(!inference.has_errors());
+
+    session.shutdown().await.unwrap();
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn applies_multi_line_and_non_ascii_edits() {
+    let root_dir = project("edits", &[("foo.ml", "let x = 1\n\nlet café = 2\n")]);
+    let mut session = connect(&root_dir, MockOptions::default()).await.unwrap();
+
+    let inference = session
+        .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+        .await
+        .unwrap();
+    assert_eq!(inference.interface, "val x : int\nval café : int\n");
+
+    session.shutdown().await.unwrap();
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn keeps_interfaces_formatting_leaves_alone() {
+    let root_dir = project("unformatted", &[("foo.ml", "let x = 1\n")]);
+    for formatting in [FormatResponse::Null, FormatResponse::NoEdits] {
+        let options = MockOptions {
+            formatting,
+            ..MockOptions::default()
+        };
+        let mut session = connect(&root_dir, options).await.unwrap();
+
+        let inference = session
+            .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+            .await
+            .unwrap();
+        assert_eq!(inference.interface, "val x  :  int\n");
+        assert!(inference.formatted);
+
+        session.shutdown().await.unwrap();
+    }
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn carries_doc_comments_over() {
+    let root_dir = project(
+        "docs",
+        &[
+            ("foo.ml", "let x = 1\nlet y = 2\n"),
+            ("foo.mli", "(** The x. *)\nval x : int\n"),
+        ],
+    );
+    let mut session = connect(&root_dir, MockOptions::default()).await.unwrap();
+
+    let inference = session
+        .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+        .await
+        .unwrap();
+    assert_eq!(
+        inference.interface,
+        "(** The x. *)\nval x : int\nval y : int\n"
+    );
+
+    session.shutdown().await.unwrap();
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn reports_diagnostics() {
+    let root_dir = project("diagnostics", &[("foo.ml", "let x = 1\nerror\n")]);
+    let mut session = connect(&root_dir, MockOptions::default()).await.unwrap();
+
+    let inference = session
+        .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+        .await
+        .unwrap();
+    assert!(inference.has_errors());
+    assert_eq!(inference.diagnostics[0].range.start.line, 1);
+
+    session.shutdown().await.unwrap();
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn forgets_the_diagnostics_of_previous_texts() {
+    let root_dir = project("fixed", &[("foo.ml", "let x = 1\nerror\n")]);
+    let mut session = connect(&root_dir, MockOptions::default()).await.unwrap();
+
+    let inference = session
+        .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+        .await
+        .unwrap();
+    assert!(inference.has_errors());
+    std::fs::write(root_dir.join("foo.ml"), "let x = 1\n").unwrap();
+    let inference = session
+        .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+        .await
+        .unwrap();
+    assert!(!inference.has_errors());
+
+    session.shutdown().await.unwrap();
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn infers_unsaved_buffers() {
+    let root_dir = project("buffers", &[("foo.ml", "let x = 1\n")]);
+    let mut session = connect(&root_dir, MockOptions::default()).await.unwrap();
+
+    let inference = session
+        .infer_buffer(
+            Path::new("foo.ml"),
+            "let z = 3\n",
+            Some(1),
+            Path::new("foo.mli"),
+        )
+        .await
+        .unwrap();
+    assert_eq!(inference.interface, "val z : int\n");
+
+    session.shutdown().await.unwrap();
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn writes_only_changed_interfaces() {
+    let root_dir = project("writes", &[("foo.ml", "let x = 1\n")]);
+    let mut session = connect(&root_dir, MockOptions::default()).await.unwrap();
+    let inference = session
+        .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+        .await
+        .unwrap();
+    session.shutdown().await.unwrap();
+
+    let mli_file = root_dir.join("foo.mli");
+    let mut outcome = InferOutcome::new(
+        &mli_file,
+        inference.interface.clone(),
+        infer_mli::encoding_rs::UTF_8,
+    )
+    .unwrap();
+    assert!(!outcome.unchanged);
+    outcome.write().unwrap();
+    assert!(outcome.wrote);
+    assert_eq!(std::fs::read_to_string(&mli_file).unwrap(), "val x : int\n");
+
+    let mut outcome = InferOutcome::new(
+        &mli_file,
+        inference.interface,
+        infer_mli::encoding_rs::UTF_8,
+    )
+    .unwrap();
+    assert!(outcome.unchanged);
+    outcome.write().unwrap();
+    assert!(!outcome.wrote);
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn checks_interfaces() {
+    let root_dir = project("check", &[("foo.ml", "let x = 1\n")]);
+    let mut session = connect(&root_dir, MockOptions::default()).await.unwrap();
+    let inference = session
+        .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+        .await
+        .unwrap();
+    session.shutdown().await.unwrap();
+
+    // what --check fails on
+    let mli_file = root_dir.join("foo.mli");
+    let status = |header| {
+        MliStatus::of(
+            &mli_file,
+            &inference.interface,
+            header,
+            infer_mli::encoding_rs::UTF_8,
+        )
+    };
+    assert_eq!(status(""), MliStatus::Missing);
+    std::fs::write(&mli_file, "val x : bool\n").unwrap();
+    assert_eq!(status(""), MliStatus::OutOfDate);
+    std::fs::write(&mli_file, &inference.interface).unwrap();
+    assert_eq!(status(""), MliStatus::UpToDate);
+    // adding a header doesn't make it out of date
+    assert_eq!(status("(* generated *)\n\n"), MliStatus::UpToDate);
+    std::fs::write(
+        &mli_file,
+        format!("(* generated *)\n\n{}", inference.interface),
+    )
+    .unwrap();
+    assert_eq!(status("(* generated *)\n\n"), MliStatus::UpToDate);
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn infers_the_next_files_after_a_timeout() {
+    let root_dir = project(
+        "timeout",
+        &[
+            ("slow.ml", "let x = 1\nhang\n"),
+            ("foo.ml", "let x = 1\n"),
+            ("bar.ml", "let y = 2\n"),
+        ],
+    );
+    let options = MockOptions {
+        request_timeout: Duration::from_secs(1),
+        ..MockOptions::default()
+    };
+    let mut session = connect(&root_dir, options).await.unwrap();
+
+    let err = session
+        .infer(Path::new("slow.ml"), Path::new("slow.mli"))
+        .await
+        .unwrap_err();
+    assert!(err.downcast_ref::<TimedOut>().is_some(), "{err}");
+    let inference = session
+        .infer(Path::new("foo.ml"), Path::new("foo.mli"))
+        .await
+        .unwrap();
+    assert_eq!(inference.interface, "val x : int\n");
+
+    // timing out again means the server hangs
+    let err = session
+        .infer(Path::new("slow.ml"), Path::new("slow.mli"))
+        .await
+        .unwrap_err();
+    assert!(err.downcast_ref::<TimedOut>().is_some(), "{err}");
+    let err = session
+        .infer(Path::new("bar.ml"), Path::new("bar.mli"))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("the server timed out"), "{err}");
+
+    session.shutdown().await.unwrap();
+    std::fs::remove_dir_all(root_dir).unwrap();
+}
+
+#[tokio::test]
+async fn fails_when_the_server_cant_infer() {
+    let root_dir = project("unsupported", &[("foo.ml", "let x = 1\n")]);
+
+    let options = MockOptions {
+        infer_intf: false,
+        ..MockOptions::default()
+    };
+    let err = connect(&root_dir, options).await.err().unwrap();
+    assert!(
+        err.to_string()
+            .contains("mock doesn't support inferring interfaces"),
+        "{err}"
+    );
+
+    std::fs::remove_dir_all(root_dir).unwrap();
+}